@@ -0,0 +1,32 @@
+extern crate test;
+extern crate clojit;
+
+use test::Bencher;
+
+use clojit::asm;
+use clojit::vm::Vm;
+
+// A counting loop, almost all of the time goes into instruction dispatch.
+// `cargo bench --features decode-each` runs it with the old loop that
+// decodes every instruction when it is fetched.
+static COUNT_LOOP : &'static str = "
+        CSHORT 1 0
+        CSHORT 2 1
+        CINT   3 100000
+loop:   ISLT   4 1 3
+        JUMPF  4 done
+        ADDVV  1 1 2
+        JUMP   0 loop
+done:   EXIT
+";
+
+#[bench]
+fn count_loop(b: &mut Bencher) {
+    let (data, code, dd) = asm::assemble(COUNT_LOOP).unwrap();
+
+    b.iter(|| {
+        let mut vm = Vm::new(data.clone(), code.clone(), dd.clone());
+        vm.start().unwrap();
+    });
+}
+
@@ -0,0 +1,87 @@
+extern crate test;
+extern crate clojit;
+
+use test::Bencher;
+
+use clojit::asm;
+use clojit::vm::Vm;
+
+// 1000 nested calls, RET only clears the slots the frames wrote so the cost
+// does not depend on the size of the slot stack.
+static COUNT_DOWN : &'static str = "
+        FNEW   2 down
+        CINT   3 1000
+        CALL   1 1
+        EXIT
+
+.func down 1
+        CSHORT 3 0
+        ISGT   4 2 3
+        JUMPF  4 done
+        CSHORT 3 1
+        SUBVV  7 2 3
+        FNEW   6 down
+        CALL   5 1
+        TRANC  0 6
+        RET    5
+done:   RET    2
+";
+
+// The same with every frame writing slot 200, so RET has more to clear.
+static COUNT_DOWN_WIDE : &'static str = "
+        FNEW   2 down
+        CINT   3 1000
+        CALL   1 1
+        EXIT
+
+.func down 1
+        CSHORT 200 0
+        CSHORT 3 0
+        ISGT   4 2 3
+        JUMPF  4 done
+        CSHORT 3 1
+        SUBVV  7 2 3
+        FNEW   6 down
+        CALL   5 1
+        TRANC  0 6
+        RET    5
+done:   RET    2
+";
+
+// Runs `src` with the slot stack grown to `slots` up front. The Vm is reused
+// so growing the stack is not part of the measurement.
+fn count_down(b: &mut Bencher, src: &str, slots: uint) {
+    let (data, code, dd) = asm::assemble(src).unwrap();
+    let mut vm = Vm::new(data, code, dd);
+    vm.grow_slots(slots).unwrap();
+
+    b.iter(|| {
+        vm.code.ip = 0;
+        vm.start().unwrap();
+    });
+}
+
+#[bench]
+fn count_down_4k_slots(b: &mut Bencher) {
+    count_down(b, COUNT_DOWN, 4096);
+}
+
+#[bench]
+fn count_down_64k_slots(b: &mut Bencher) {
+    count_down(b, COUNT_DOWN, 65536);
+}
+
+#[bench]
+fn count_down_1m_slots(b: &mut Bencher) {
+    count_down(b, COUNT_DOWN, 1000000);
+}
+
+#[bench]
+fn count_down_wide_frames_4k_slots(b: &mut Bencher) {
+    count_down(b, COUNT_DOWN_WIDE, 4096);
+}
+
+#[bench]
+fn count_down_wide_frames_1m_slots(b: &mut Bencher) {
+    count_down(b, COUNT_DOWN_WIDE, 1000000);
+}
@@ -0,0 +1,511 @@
+// Assembler for a textual form of the bytecode.
+//
+//     ; comments run to the end of the line
+//     .const greeting "hello"     ; named constant, usable as an operand
+//     .type Point x y:mut         ; type with an immutable and a mutable field
+//     .vtable 0 Point show-point  ; vtable entry 0 for Point is show-point
+//     .arity 0 1 area-1           ; multi-arity function 0 with 1 argument
+//     .arity 0 2+ area-n          ; and with 2 or more arguments
+//     .line "core.clj" 12 3       ; following code is from line 12, column 3
+//     .catch try end handler 4 Ex ; exceptions of type Ex thrown between the
+//                                 ; labels try and end go to handler, in slot 4,
+//                                 ; without a type everything is caught
+//
+//             NSGETS 1 "println"
+//             CSTR   2 greeting
+//             CALL   0 1
+//     loop:   JUMP   0 loop       ; jump offsets are computed from labels
+//             EXIT
+//
+//     .func show-point 1          ; label plus FUNCF 1, .funcv emits FUNCV
+//             RET    2
+//
+// Operands are positional and missing trailing operands are 0, like missing
+// fields in the JSON format. String, keyword (:key), int and float literals
+// given to CSTR, CKEY, CINT, CFLOAT, NSGETS and NSSETS are pooled into the
+// constant tables, JUMP/JUMPF/JUMPT take a label for their relative offset,
+// FNEW and every other operand take a label for its absolute index.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::num::FromPrimitive;
+use std::collections::HashMap;
+
+use vm;
+use vm::OpCode;
+use vm::Data;
+use vm::Code;
+use vm::LineEntry;
+use vm::Handler;
+use vm::DispatchData;
+use vm::Arities;
+use vm::{CljType, CljField};
+
+use decode::ToInstr;
+use decode::{OpABC, OpAD};
+
+pub struct AsmError {
+    pub line : uint,
+    pub msg  : String,
+}
+
+impl fmt::Show for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+#[deriving(Clone, Show)]
+enum Operand {
+    Num(i64),
+    Flt(f64),
+    Text(String),
+    Keyword(String),
+    Name(String),
+}
+
+// How an operand position is encoded.
+enum Encoding {
+    Plain,
+    StrPool,
+    KeyPool,
+    IntPool,
+    FloatPool,
+    TypeIndex,
+    Relative,
+}
+
+struct Line {
+    line : uint,
+    op   : OpCode,
+    args : Vec<Operand>,
+}
+
+struct Assembler {
+    data   : Data,
+    consts : HashMap<String, Operand>,
+    labels : HashMap<String, uint>,
+    lines  : Vec<Line>,
+    // (line, vfunc, type, label), resolved once all labels are known.
+    vtable : Vec<(uint, uint, uint, String)>,
+    // (line, arities, nargs, variadic, label), resolved the same way.
+    arities : Vec<(uint, uint, uint, bool, String)>,
+    // The labels of .func and .funcv, used as function names.
+    names  : HashMap<uint, String>,
+    // The open .line range, closed by the next .line or the end.
+    line_start : Option<(uint, String, uint, uint)>,
+    line_table : Vec<LineEntry>,
+    // (line, start, end, target, slot, type), labels resolved at the end.
+    catches : Vec<(uint, String, String, String, uint, Option<uint>)>,
+    opcodes : HashMap<String, OpCode>,
+}
+
+fn err<T>(line: uint, msg: String) -> Result<T, AsmError> {
+    Err(AsmError { line: line, msg: msg })
+}
+
+fn tokenize(line_nr: uint, line: &str) -> Result<Vec<String>, AsmError> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break
+        };
+
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() || c == ',' {
+            continue;
+        } else if c == '"' {
+            // The leading quote marks the token as a string literal.
+            let mut token = String::from_str("\"");
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => token.push('\n'),
+                        Some('t') => token.push('\t'),
+                        Some(c) => token.push(c),
+                        None => return err(line_nr, "unterminated string".to_string())
+                    },
+                    Some(c) => token.push(c),
+                    None => return err(line_nr, "unterminated string".to_string())
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            token.push(c);
+            loop {
+                match chars.peek() {
+                    Some(&c) if !c.is_whitespace() && c != ',' && c != ';' => {
+                        token.push(c);
+                        chars.next();
+                    }
+                    _ => break
+                }
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_operand(token: &str) -> Operand {
+    if token.starts_with("\"") {
+        Text(token.slice_from(1).to_string())
+    } else if token.starts_with(":") {
+        Keyword(token.slice_from(1).to_string())
+    } else {
+        match from_str::<i64>(token) {
+            Some(n) => Num(n),
+            None => match from_str::<f64>(token) {
+                Some(f) => Flt(f),
+                None => Name(token.to_string())
+            }
+        }
+    }
+}
+
+fn encoding(op: OpCode, pos: uint) -> Encoding {
+    match (op, pos) {
+        (vm::CSTR, 1) | (vm::NSGETS, 1) | (vm::NSSETS, 1) => StrPool,
+        (vm::CKEY, 1) => KeyPool,
+        (vm::CINT, 1) => IntPool,
+        (vm::CFLOAT, 1) => FloatPool,
+        (vm::CTYPE, 1) => TypeIndex,
+        (vm::JUMP, 1) | (vm::JUMPF, 1) | (vm::JUMPT, 1) => Relative,
+        _ => Plain
+    }
+}
+
+fn intern<T: PartialEq + Clone>(pool: &mut Vec<T>, val: &T) -> i64 {
+    match pool.iter().position(|v| v == val) {
+        Some(idx) => idx as i64,
+        None => {
+            pool.push(val.clone());
+            (pool.len() - 1) as i64
+        }
+    }
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        let mut opcodes = HashMap::new();
+        for i in range(0u, 256) {
+            let op: Option<OpCode> = FromPrimitive::from_uint(i);
+            match op {
+                Some(op) => { opcodes.insert(op.to_string(), op); }
+                None => break
+            }
+        }
+
+        Assembler {
+            data   : Data { cint: vec![], cfloat: vec![], cstr: vec![],
+                            ckey: vec![], ctype: vec![] },
+            consts : HashMap::new(),
+            labels : HashMap::new(),
+            lines  : vec![],
+            vtable : vec![],
+            arities : vec![],
+            names  : HashMap::new(),
+            line_start : None,
+            line_table : vec![],
+            catches : vec![],
+            opcodes : opcodes,
+        }
+    }
+
+    fn close_line(&mut self) {
+        let end = self.lines.len();
+        match self.line_start.take() {
+            Some((start, file, line, column)) if start < end =>
+                self.line_table.push(LineEntry { start: start, end: end, file: file,
+                                                 line: line, column: column }),
+            _ => ()
+        }
+    }
+
+    fn define_label(&mut self, line: uint, name: &str) -> Result<(), AsmError> {
+        let ip = self.lines.len();
+        if self.labels.insert(name.to_string(), ip) {
+            Ok(())
+        } else {
+            err(line, format!("label {} defined twice", name))
+        }
+    }
+
+    fn directive(&mut self, line: uint, tokens: &[String]) -> Result<(), AsmError> {
+        match tokens[0].as_slice() {
+            ".const" if tokens.len() == 3 => {
+                let val = parse_operand(tokens[2].as_slice());
+                self.consts.insert(tokens[1].clone(), val);
+            },
+            ".func" | ".funcv" if tokens.len() == 2 || tokens.len() == 3 => {
+                try!(self.define_label(line, tokens[1].as_slice()));
+                self.names.insert(self.lines.len(), tokens[1].clone());
+                let op = if tokens[0].as_slice() == ".func" { vm::FUNCF } else { vm::FUNCV };
+                let args = tokens.slice_from(2).iter()
+                                 .map(|t| parse_operand(t.as_slice())).collect();
+                self.lines.push(Line { line: line, op: op, args: args });
+            },
+            ".type" if tokens.len() >= 2 => {
+                let mut fields = vec![];
+                for (offset, field) in tokens.slice_from(2).iter().enumerate() {
+                    let field = field.as_slice();
+                    let (name, mutable) = if field.ends_with(":mut") {
+                        (field.slice_to(field.len() - 4), true)
+                    } else {
+                        (field, false)
+                    };
+                    fields.push(CljField { name: name.to_string(), offset: offset,
+                                           mutable: mutable });
+                }
+                let nr = self.data.ctype.len();
+                self.data.ctype.push(CljType { name: tokens[1].clone(), nr: nr,
+                                               size: fields.len(), fields: fields });
+            },
+            ".vtable" if tokens.len() == 4 => {
+                let vfunc = match from_str::<uint>(tokens[1].as_slice()) {
+                    Some(n) => n,
+                    None => return err(line, format!("invalid vtable index {}", tokens[1]))
+                };
+                let ty = match self.data.ctype.iter().find(|t| t.name == tokens[2]) {
+                    Some(t) => t.nr,
+                    None => return err(line, format!("unknown type {}", tokens[2]))
+                };
+                self.vtable.push((line, vfunc, ty, tokens[3].clone()));
+            },
+            ".line" if tokens.len() == 3 || tokens.len() == 4 => {
+                let nums: Vec<Option<uint>> = tokens.slice_from(2).iter()
+                                                    .map(|t| from_str(t.as_slice())).collect();
+                let (line_nr, column) = match nums.as_slice() {
+                    [Some(l)] => (l, 0),
+                    [Some(l), Some(c)] => (l, c),
+                    _ => return err(line, format!("invalid line {}", tokens.slice_from(2).connect(" ")))
+                };
+                self.close_line();
+                let file = tokens[1].as_slice().trim_chars('"').to_string();
+                self.line_start = Some((self.lines.len(), file, line_nr, column));
+            },
+            ".catch" if tokens.len() == 5 || tokens.len() == 6 => {
+                let slot = match from_str::<uint>(tokens[4].as_slice()) {
+                    Some(n) if n < 256 => n,
+                    _ => return err(line, format!("invalid slot {}", tokens[4]))
+                };
+                let ty = if tokens.len() == 6 {
+                    match self.data.ctype.iter().find(|t| t.name == tokens[5]) {
+                        Some(t) => Some(t.nr),
+                        None => return err(line, format!("unknown type {}", tokens[5]))
+                    }
+                } else {
+                    None
+                };
+                self.catches.push((line, tokens[1].clone(), tokens[2].clone(),
+                                   tokens[3].clone(), slot, ty));
+            },
+            ".arity" if tokens.len() == 4 => {
+                let arities = match from_str::<uint>(tokens[1].as_slice()) {
+                    Some(n) => n,
+                    None => return err(line, format!("invalid arity table index {}", tokens[1]))
+                };
+                let nargs = tokens[2].as_slice();
+                let variadic = nargs.ends_with("+");
+                let nargs = match from_str::<uint>(nargs.trim_right_chars('+')) {
+                    Some(n) => n,
+                    None => return err(line, format!("invalid argument count {}", nargs))
+                };
+                self.arities.push((line, arities, nargs, variadic, tokens[3].clone()));
+            },
+            _ => return err(line, format!("invalid directive: {}", tokens.connect(" ")))
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: uint, text: &str) -> Result<(), AsmError> {
+        let mut tokens = try!(tokenize(line, text));
+
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        if tokens[0].as_slice().ends_with(":") {
+            let label = tokens.remove(0).unwrap();
+            try!(self.define_label(line, label.as_slice().slice_to(label.len() - 1)));
+            if tokens.is_empty() {
+                return Ok(());
+            }
+        }
+
+        if tokens[0].as_slice().starts_with(".") {
+            return self.directive(line, tokens.as_slice());
+        }
+
+        let op = match self.opcodes.find(&tokens[0]) {
+            Some(op) => *op,
+            None => return err(line, format!("unknown opcode {}", tokens[0]))
+        };
+
+        let max_args = match op.ty() { vm::TyABC => 3, vm::TyAD => 2 };
+        if tokens.len() - 1 > max_args {
+            return err(line, format!("{} takes at most {} operands", op, max_args));
+        }
+
+        let args = tokens.slice_from(1).iter().map(|t| parse_operand(t.as_slice())).collect();
+        self.lines.push(Line { line: line, op: op, args: args });
+        Ok(())
+    }
+
+    fn resolve_name(&self, line: uint, arg: &Operand) -> Result<Operand, AsmError> {
+        match *arg {
+            Name(ref name) => match self.consts.find(name) {
+                Some(val) => Ok(val.clone()),
+                None => match self.labels.find(name) {
+                    Some(ip) => Ok(Num(*ip as i64)),
+                    None => err(line, format!("undefined name {}", name))
+                }
+            },
+            ref val => Ok(val.clone())
+        }
+    }
+
+    fn encode_operand(&mut self, ip: uint, line: &Line, pos: uint) -> Result<i64, AsmError> {
+        let arg = match line.args.as_slice().get(pos) {
+            Some(arg) => arg.clone(),
+            None => return Ok(0)
+        };
+
+        // Type names are looked up in the type table, not among the labels.
+        match (encoding(line.op, pos), &arg) {
+            (TypeIndex, &Name(ref name)) if !self.consts.contains_key(name) =>
+                return match self.data.ctype.iter().find(|t| t.name == *name) {
+                    Some(t) => Ok(t.nr as i64),
+                    None => err(line.line, format!("unknown type {}", name))
+                },
+            _ => ()
+        }
+
+        let val = try!(self.resolve_name(line.line, &arg));
+
+        match (encoding(line.op, pos), val) {
+            (StrPool, Text(s))    => Ok(intern(&mut self.data.cstr, &s)),
+            (KeyPool, Keyword(k)) => Ok(intern(&mut self.data.ckey, &k)),
+            (IntPool, Num(n))     => Ok(intern(&mut self.data.cint, &n)),
+            (FloatPool, Flt(f))   => Ok(intern(&mut self.data.cfloat, &f)),
+            (FloatPool, Num(n))   => Ok(intern(&mut self.data.cfloat, &(n as f64))),
+            (TypeIndex, Num(n))   => Ok(n),
+            (TypeIndex, _)        => err(line.line, format!("invalid type operand {}", arg)),
+            (Relative, Num(target)) => match arg {
+                Name(ref name) if self.labels.contains_key(name) => Ok(target - ip as i64),
+                _ => Ok(target)
+            },
+            (Plain, Num(n))       => Ok(n),
+            (_, val) => err(line.line, format!("invalid operand {} for {}", val, line.op))
+        }
+    }
+
+    fn finish(mut self) -> Result<(Data, Code, DispatchData), AsmError> {
+        self.close_line();
+        let lines = mem::replace(&mut self.lines, vec![]);
+        let mut func = Vec::with_capacity(lines.len());
+
+        for (ip, line) in lines.iter().enumerate() {
+            let mut ops = [0i64, ..3];
+            for pos in range(0, line.args.len()) {
+                ops[pos] = try!(self.encode_operand(ip, line, pos));
+            }
+
+            let instr = match line.op.ty() {
+                vm::TyABC => {
+                    for &n in ops.iter() {
+                        if n < 0 || n > 255 {
+                            return err(line.line, format!("operand {} does not fit in 8 bits", n));
+                        }
+                    }
+                    OpABC { op: line.op as u8, a: ops[0] as u8,
+                            b: ops[1] as u8, c: ops[2] as u8 }.to_instr()
+                },
+                vm::TyAD => {
+                    if ops[0] < 0 || ops[0] > 255 {
+                        return err(line.line, format!("operand {} does not fit in 8 bits", ops[0]));
+                    }
+                    if ops[1] < -32768 || ops[1] > 65535 {
+                        return err(line.line, format!("operand {} does not fit in 16 bits", ops[1]));
+                    }
+                    OpAD { op: line.op as u8, a: ops[0] as u8, d: ops[1] as u16 }.to_instr()
+                }
+            };
+            func.push(instr);
+        }
+
+        let mut vtable = HashMap::new();
+        for &(line, vfunc, ty, ref label) in self.vtable.iter() {
+            let ip = match self.labels.find(label) {
+                Some(ip) => *ip,
+                None => return err(line, format!("undefined label {}", label))
+            };
+            vtable.find_or_insert_with(vfunc, |_| HashMap::new()).insert(ty, ip);
+        }
+
+        let mut arities = HashMap::new();
+        for &(line, table, nargs, variadic, ref label) in self.arities.iter() {
+            let ip = match self.labels.find(label) {
+                Some(ip) => *ip,
+                None => return err(line, format!("undefined label {}", label))
+            };
+            let entry = arities.find_or_insert_with(table, |_| Arities {
+                fixed: HashMap::new(),
+                variadic: None
+            });
+            if variadic {
+                entry.variadic = Some((nargs, ip));
+            } else {
+                entry.fixed.insert(nargs, ip);
+            }
+        }
+
+        let mut handlers = vec![];
+        for &(line, ref start, ref end, ref target, slot, catch_type) in self.catches.iter() {
+            let mut ips = vec![];
+            for label in [start, end, target].iter() {
+                match self.labels.find(*label) {
+                    Some(ip) => ips.push(*ip),
+                    None => return err(line, format!("undefined label {}", label))
+                }
+            }
+            handlers.push(Handler { start: ips[0], end: ips[1], target: ips[2],
+                                    catch_type: catch_type, slot: slot });
+        }
+
+        let mut code = Code::new(func);
+        // Opcodes come from the opcode table, they always decode.
+        code.decode().unwrap();
+        code.handlers = handlers;
+        code.names = self.names;
+        code.lines = self.line_table;
+
+        Ok((self.data, code, DispatchData { vtable: vtable, arities: arities }))
+    }
+}
+
+pub fn assemble(src: &str) -> Result<(Data, Code, DispatchData), AsmError> {
+    let mut asm = Assembler::new();
+
+    for (nr, line) in src.lines().enumerate() {
+        try!(asm.parse_line(nr + 1, line));
+    }
+
+    asm.finish()
+}
+
+pub fn assemble_file(path: &Path) -> Result<(Data, Code, DispatchData), String> {
+    let src = match io::File::open(path).read_to_string() {
+        Ok(src) => src,
+        Err(err) => return Err(err.to_string())
+    };
+
+    assemble(src.as_slice()).map_err(|err| format!("{}: {}", path.display(), err))
+}
@@ -0,0 +1,36 @@
+extern crate clojit;
+
+use std::io;
+use std::os;
+
+use clojit::load;
+use clojit::disasm;
+
+fn main() {
+    let args = os::args();
+    if args.len() != 2 {
+        println!("usage: {} input.json|input.asm|input.cljb", args[0]);
+        os::set_exit_status(1);
+        return;
+    }
+
+    let path = Path::new(args[1].as_slice());
+
+    let (data, code, dispatchdata) = match load::load(&path) {
+        Ok(program) => program,
+        Err(err) => {
+            println!("{}", err);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    let mut out = io::stdout();
+    match disasm::disassemble(&mut out, &data, &code, &dispatchdata) {
+        Ok(()) => (),
+        Err(err) => {
+            println!("{}", err);
+            os::set_exit_status(1);
+        }
+    }
+}
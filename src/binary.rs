@@ -0,0 +1,314 @@
+// Binary bytecode container, a compact alternative to the JSON format.
+//
+// All integers are little endian, strings are a u32 byte length followed by
+// UTF-8 bytes.
+//
+//     magic    "CLJB"
+//     version  u16
+//     CINT     u32 count, i64 *
+//     CFLOAT   u32 count, f64 *
+//     CSTR     u32 count, str *
+//     CKEY     u32 count, str *
+//     types    u32 count, (name:str nr:u32 size:u32
+//                          u32 count, (name:str offset:u32 mutable:u8) *) *
+//     vtable   u32 count, (vfunc:u32 u32 count, (type:u32 func:u32) *) *
+//     arities  u32 count, (table:u32 u32 count, (nargs:u32 func:u32) *
+//                          variadic:u8 [fixed:u32 func:u32]) *
+//     code     u32 count, u32 *
+//     names    u32 count, (func:u32 name:str) *
+//     lines    u32 count, (start:u32 end:u32 file:str line:u32 column:u32) *
+//     handlers u32 count, (start:u32 end:u32 target:u32 type:u32 slot:u8) *
+//
+// A handler type of NO_CATCH_TYPE catches everything.
+//
+// Instructions are stored as raw Instr words, so the opcode numbers are the
+// discriminants of vm::OpCode. VERSION has to be bumped whenever OpCode
+// changes.
+
+use std::io;
+use std::io::{IoResult, IoError, BufferedReader, BufferedWriter};
+use std::io::util::LimitReader;
+use std::collections::HashMap;
+
+use vm::Instr;
+use vm::Data;
+use vm::Code;
+use vm::LineEntry;
+use vm::Handler;
+use vm::DispatchData;
+use vm::Arities;
+use vm::{CljType, CljField};
+
+pub static MAGIC : &'static [u8] = b"CLJB";
+pub static VERSION : u16 = 7;
+
+static NO_CATCH_TYPE : u32 = 0xffffffff;
+
+fn invalid(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+// Checks the magic bytes, a file too short to hold them is not binary.
+pub fn is_binary(path: &Path) -> IoResult<bool> {
+    let mut file = try!(io::File::open(path));
+    match file.read_exact(MAGIC.len()) {
+        Ok(magic) => Ok(magic.as_slice() == MAGIC),
+        Err(ref err) if err.kind == io::EndOfFile => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+// ----------------------------- Reader -----------------------------
+
+// Lengths come from the file, so nothing is allocated up front for them.
+// A truncated file runs out of bytes long before a bogus length could
+// exhaust memory.
+fn read_str<R: Reader>(r: &mut R) -> IoResult<String> {
+    let len = try!(r.read_le_u32()) as uint;
+    let bytes = try!(LimitReader::new(r.by_ref(), len).read_to_end());
+    if bytes.len() < len {
+        return Err(invalid("truncated string"));
+    }
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(invalid("string is not valid UTF-8"))
+    }
+}
+
+fn read_vec<R: Reader, T>(r: &mut R, f: |&mut R| -> IoResult<T>) -> IoResult<Vec<T>> {
+    let len = try!(r.read_le_u32()) as uint;
+    let mut vec = Vec::new();
+    for _ in range(0, len) {
+        vec.push(try!(f(r)));
+    }
+    Ok(vec)
+}
+
+fn read_field<R: Reader>(r: &mut R) -> IoResult<CljField> {
+    let name = try!(read_str(r));
+    let offset = try!(r.read_le_u32()) as uint;
+    let mutable = try!(r.read_u8()) != 0;
+    Ok(CljField { name: name, offset: offset, mutable: mutable })
+}
+
+fn read_type<R: Reader>(r: &mut R) -> IoResult<CljType> {
+    let name = try!(read_str(r));
+    let nr = try!(r.read_le_u32()) as uint;
+    let size = try!(r.read_le_u32()) as uint;
+    let fields = try!(read_vec(r, read_field));
+    Ok(CljType { name: name, nr: nr, size: size, fields: fields })
+}
+
+pub fn read(path: &Path) -> IoResult<(Data, Code, DispatchData)> {
+    let mut r = BufferedReader::new(try!(io::File::open(path)));
+
+    let magic = try!(r.read_exact(MAGIC.len()));
+    if magic.as_slice() != MAGIC {
+        return Err(invalid("not a clojit bytecode file"));
+    }
+
+    if try!(r.read_le_u16()) != VERSION {
+        return Err(invalid("unsupported bytecode version"));
+    }
+
+    let cint = try!(read_vec(&mut r, |r| r.read_le_i64()));
+    let cfloat = try!(read_vec(&mut r, |r| r.read_le_f64()));
+    let cstr = try!(read_vec(&mut r, read_str));
+    let ckey = try!(read_vec(&mut r, read_str));
+    let ctype = try!(read_vec(&mut r, read_type));
+
+    let mut vtable = HashMap::new();
+    let entries = try!(r.read_le_u32());
+    for _ in range(0, entries) {
+        let vfunc = try!(r.read_le_u32()) as uint;
+        let mut impls = HashMap::new();
+        let count = try!(r.read_le_u32());
+        for _ in range(0, count) {
+            let ty = try!(r.read_le_u32()) as uint;
+            let func = try!(r.read_le_u32()) as uint;
+            impls.insert(ty, func);
+        }
+        vtable.insert(vfunc, impls);
+    }
+
+    let mut arities = HashMap::new();
+    let entries = try!(r.read_le_u32());
+    for _ in range(0, entries) {
+        let table = try!(r.read_le_u32()) as uint;
+        let mut fixed = HashMap::new();
+        let count = try!(r.read_le_u32());
+        for _ in range(0, count) {
+            let nargs = try!(r.read_le_u32()) as uint;
+            let func = try!(r.read_le_u32()) as uint;
+            fixed.insert(nargs, func);
+        }
+        let variadic = if try!(r.read_u8()) != 0 {
+            let nargs = try!(r.read_le_u32()) as uint;
+            let func = try!(r.read_le_u32()) as uint;
+            Some((nargs, func))
+        } else {
+            None
+        };
+        arities.insert(table, Arities { fixed: fixed, variadic: variadic });
+    }
+
+    let func = try!(read_vec(&mut r, |r| r.read_le_u32().map(Instr)));
+
+    let data = Data {
+        cint   : cint,
+        cfloat : cfloat,
+        cstr   : cstr,
+        ckey   : ckey,
+        ctype  : ctype
+    };
+
+    let mut code = Code::new(func);
+    if code.decode().is_err() {
+        return Err(invalid("invalid opcode"));
+    }
+
+    let count = try!(r.read_le_u32());
+    for _ in range(0, count) {
+        let func = try!(r.read_le_u32()) as uint;
+        let name = try!(read_str(&mut r));
+        code.names.insert(func, name);
+    }
+
+    let count = try!(r.read_le_u32());
+    for _ in range(0, count) {
+        let start = try!(r.read_le_u32()) as uint;
+        let end = try!(r.read_le_u32()) as uint;
+        let file = try!(read_str(&mut r));
+        let line = try!(r.read_le_u32()) as uint;
+        let column = try!(r.read_le_u32()) as uint;
+        code.lines.push(LineEntry { start: start, end: end, file: file,
+                                    line: line, column: column });
+    }
+
+    let count = try!(r.read_le_u32());
+    for _ in range(0, count) {
+        let start = try!(r.read_le_u32()) as uint;
+        let end = try!(r.read_le_u32()) as uint;
+        let target = try!(r.read_le_u32()) as uint;
+        let catch_type = match try!(r.read_le_u32()) {
+            nr if nr == NO_CATCH_TYPE => None,
+            nr => Some(nr as uint)
+        };
+        let slot = try!(r.read_u8()) as uint;
+        code.handlers.push(Handler { start: start, end: end, target: target,
+                                     catch_type: catch_type, slot: slot });
+    }
+
+    Ok((data, code, DispatchData { vtable: vtable, arities: arities }))
+}
+
+// ----------------------------- Writer -----------------------------
+
+fn write_str<W: Writer>(w: &mut W, s: &str) -> IoResult<()> {
+    try!(w.write_le_u32(s.len() as u32));
+    w.write_str(s)
+}
+
+pub fn write(path: &Path, data: &Data, code: &Code, dd: &DispatchData) -> IoResult<()> {
+    let mut w = BufferedWriter::new(try!(io::File::create(path)));
+
+    try!(w.write(MAGIC));
+    try!(w.write_le_u16(VERSION));
+
+    try!(w.write_le_u32(data.cint.len() as u32));
+    for i in data.cint.iter() {
+        try!(w.write_le_i64(*i));
+    }
+
+    try!(w.write_le_u32(data.cfloat.len() as u32));
+    for f in data.cfloat.iter() {
+        try!(w.write_le_f64(*f));
+    }
+
+    try!(w.write_le_u32(data.cstr.len() as u32));
+    for s in data.cstr.iter() {
+        try!(write_str(&mut w, s.as_slice()));
+    }
+
+    try!(w.write_le_u32(data.ckey.len() as u32));
+    for k in data.ckey.iter() {
+        try!(write_str(&mut w, k.as_slice()));
+    }
+
+    try!(w.write_le_u32(data.ctype.len() as u32));
+    for t in data.ctype.iter() {
+        try!(write_str(&mut w, t.name.as_slice()));
+        try!(w.write_le_u32(t.nr as u32));
+        try!(w.write_le_u32(t.size as u32));
+        try!(w.write_le_u32(t.fields.len() as u32));
+        for f in t.fields.iter() {
+            try!(write_str(&mut w, f.name.as_slice()));
+            try!(w.write_le_u32(f.offset as u32));
+            try!(w.write_u8(f.mutable as u8));
+        }
+    }
+
+    try!(w.write_le_u32(dd.vtable.len() as u32));
+    for (vfunc, impls) in dd.vtable.iter() {
+        try!(w.write_le_u32(*vfunc as u32));
+        try!(w.write_le_u32(impls.len() as u32));
+        for (ty, func) in impls.iter() {
+            try!(w.write_le_u32(*ty as u32));
+            try!(w.write_le_u32(*func as u32));
+        }
+    }
+
+    try!(w.write_le_u32(dd.arities.len() as u32));
+    for (table, arities) in dd.arities.iter() {
+        try!(w.write_le_u32(*table as u32));
+        try!(w.write_le_u32(arities.fixed.len() as u32));
+        for (nargs, func) in arities.fixed.iter() {
+            try!(w.write_le_u32(*nargs as u32));
+            try!(w.write_le_u32(*func as u32));
+        }
+        match arities.variadic {
+            Some((nargs, func)) => {
+                try!(w.write_u8(1));
+                try!(w.write_le_u32(nargs as u32));
+                try!(w.write_le_u32(func as u32));
+            }
+            None => try!(w.write_u8(0))
+        }
+    }
+
+    try!(w.write_le_u32(code.func.len() as u32));
+    for instr in code.func.iter() {
+        let Instr(word) = *instr;
+        try!(w.write_le_u32(word));
+    }
+
+    try!(w.write_le_u32(code.names.len() as u32));
+    for (func, name) in code.names.iter() {
+        try!(w.write_le_u32(*func as u32));
+        try!(write_str(&mut w, name.as_slice()));
+    }
+
+    try!(w.write_le_u32(code.lines.len() as u32));
+    for e in code.lines.iter() {
+        try!(w.write_le_u32(e.start as u32));
+        try!(w.write_le_u32(e.end as u32));
+        try!(write_str(&mut w, e.file.as_slice()));
+        try!(w.write_le_u32(e.line as u32));
+        try!(w.write_le_u32(e.column as u32));
+    }
+
+    try!(w.write_le_u32(code.handlers.len() as u32));
+    for h in code.handlers.iter() {
+        try!(w.write_le_u32(h.start as u32));
+        try!(w.write_le_u32(h.end as u32));
+        try!(w.write_le_u32(h.target as u32));
+        try!(w.write_le_u32(h.catch_type.map_or(NO_CATCH_TYPE, |nr| nr as u32)));
+        try!(w.write_u8(h.slot as u8));
+    }
+
+    w.flush()
+}
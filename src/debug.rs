@@ -0,0 +1,190 @@
+use std::io;
+use std::cmp;
+use std::io::BufferedReader;
+use std::io::stdio::StdReader;
+use std::collections::HashSet;
+
+use vm;
+use vm::Vm;
+use vm::Instr;
+use vm::InstrPtr;
+
+use decode::Decode;
+use disasm;
+
+enum Mode {
+    Continue,
+    Step,
+    // Stop once the Vm.stack depth is back to at most the given depth.
+    StepOver(uint),
+    // Stop once the Vm.stack depth drops below the given depth.
+    StepOut(uint),
+}
+
+pub enum Action {
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    pub breakpoints : HashSet<InstrPtr>,
+    pub break_on_entry : bool,
+    mode  : Mode,
+    input : BufferedReader<StdReader>,
+}
+
+static HELP : &'static str = "\
+s, step            execute one instruction
+n, next            step over CALL and APPLY
+o, out             run until the current function returns
+c, continue        run until the next breakpoint
+b, break IP        set a breakpoint on instruction IP
+d, delete IP       delete the breakpoint on instruction IP
+e, entry           toggle breaking on every FUNCF/FUNCV
+l, list [N]        show N instructions around the current one
+f, frame [N]       show the first N slots of the current frame
+bt, stack          show the saved contexts on the Vm stack
+sym [NAME]         show the symbol table or a single binding
+q, quit            stop the program";
+
+impl Debugger {
+    // Starts in single step mode, so the user gets a prompt before the
+    // first instruction.
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints : HashSet::new(),
+            break_on_entry : false,
+            mode  : Step,
+            input : io::stdin(),
+        }
+    }
+
+    fn should_stop(&self, vm: &Vm, instr: &Instr) -> bool {
+        if self.breakpoints.contains(&vm.code.ip) {
+            return true;
+        }
+
+        match instr.decode() {
+            Ok(vm::FUNCF) | Ok(vm::FUNCV) if self.break_on_entry => return true,
+            _ => ()
+        }
+
+        match self.mode {
+            Continue => false,
+            Step => true,
+            StepOver(depth) => vm.stack.len() <= depth,
+            StepOut(depth) => vm.stack.len() < depth,
+        }
+    }
+
+    // Called by Vm::start before `instr` executes.
+    pub fn hook(&mut self, vm: &Vm, instr: &Instr) -> Action {
+        if !self.should_stop(vm, instr) {
+            return Resume;
+        }
+
+        self.mode = Continue;
+        if vm.code.line_entry(vm.code.ip).is_some() {
+            println!("{}", vm.code.stack_frame(vm.code.ip));
+        }
+        show_instr(vm, vm.code.ip);
+
+        loop {
+            print!("(cdb) ");
+            io::stdio::flush();
+            let line = match self.input.read_line() {
+                Ok(line) => line,
+                Err(_) => return Quit
+            };
+
+            let words: Vec<&str> = line.as_slice().words().collect();
+            let arg = words.as_slice().get(1).and_then(|w| from_str::<uint>(*w));
+
+            match words.as_slice().get(0).map(|w| *w) {
+                Some("s") | Some("step") | None => {
+                    self.mode = Step;
+                    return Resume;
+                }
+                Some("n") | Some("next") => {
+                    self.mode = match instr.decode() {
+                        Ok(vm::CALL) | Ok(vm::APPLY) => StepOver(vm.stack.len()),
+                        _ => Step
+                    };
+                    return Resume;
+                }
+                Some("o") | Some("out") => {
+                    self.mode = StepOut(vm.stack.len());
+                    return Resume;
+                }
+                Some("c") | Some("continue") => return Resume,
+                Some("q") | Some("quit") => return Quit,
+                Some("b") | Some("break") => match arg {
+                    Some(ip) if ip < vm.code.func.len() => {
+                        self.breakpoints.insert(ip);
+                        println!("breakpoint at {}", ip);
+                    }
+                    _ => println!("break needs an instruction index")
+                },
+                Some("d") | Some("delete") => match arg {
+                    Some(ip) if self.breakpoints.remove(&ip) => println!("deleted {}", ip),
+                    _ => println!("no such breakpoint")
+                },
+                Some("e") | Some("entry") => {
+                    self.break_on_entry = !self.break_on_entry;
+                    println!("break on function entry: {}", self.break_on_entry);
+                }
+                Some("l") | Some("list") => {
+                    let n = arg.unwrap_or(5);
+                    let from = if vm.code.ip > n { vm.code.ip - n } else { 0 };
+                    let to = ::std::cmp::min(vm.code.ip + n + 1, vm.code.func.len());
+                    for ip in range(from, to) {
+                        show_instr(vm, ip);
+                    }
+                }
+                Some("f") | Some("frame") => show_frame(vm, arg.unwrap_or(10)),
+                Some("bt") | Some("stack") => show_stack(vm),
+                Some("sym") => show_symbols(vm, words.as_slice().get(1).map(|w| *w)),
+                Some("h") | Some("help") => println!("{}", HELP),
+                Some(cmd) => println!("unknown command {}, try help", cmd)
+            }
+        }
+    }
+}
+
+fn show_instr(vm: &Vm, ip: InstrPtr) {
+    let marker = if ip == vm.code.ip { "=>" } else { "  " };
+    let text = vm.code.func[ip].to_string();
+    match disasm::operand_comment(&vm.data, &vm.code, ip) {
+        Some(comment) => println!("{} {:>6}    {:<28} ; {}", marker, ip, text, comment),
+        None => println!("{} {:>6}    {}", marker, ip, text)
+    }
+}
+
+// Slots are shown relative to the frame base, like the instructions see them.
+fn show_frame(vm: &Vm, n: uint) {
+    println!("frame base {}", vm.slots.base);
+    let n = cmp::min(n, vm.slots.slot.len() - vm.slots.base);
+    for i in range(0, n) {
+        println!("  [{}] {}", i, vm.slots[i]);
+    }
+}
+
+fn show_stack(vm: &Vm) {
+    let show = |depth: uint, base: uint, ip: InstrPtr| {
+        println!("  #{} {}, base {}", depth, vm.code.stack_frame(ip), base);
+    };
+
+    show(0, vm.slots.base, vm.code.ip);
+    for (depth, ctx) in vm.stack.iter().rev().enumerate() {
+        show(depth + 1, ctx.base, ctx.ip);
+    }
+}
+
+fn show_symbols(vm: &Vm, name: Option<&str>) {
+    for (sym, binding) in vm.symbol_table.iter() {
+        if name.map_or(true, |n| n == sym.as_slice()) {
+            let dynamic = if binding.dynamic { " (dynamic)" } else { "" };
+            println!("  {} = {}{}", sym, binding.val, dynamic);
+        }
+    }
+}
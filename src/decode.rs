@@ -2,9 +2,10 @@ use std::mem;
 
 use vm::Instr;
 use vm::OpCode;
+use vm::InstrPtr;
 
 pub trait Decode {
-    fn decode(self) -> OpCode;
+    fn decode(self) -> Result<OpCode, u8>;
 }
 
 pub trait FromInstr {
@@ -27,6 +28,19 @@ pub struct OpAD {
     pub a: u8, pub op: u8
 }
 
+// An instruction with its opcode already validated and converted, built
+// once per program by `predecode`.
+#[deriving(Clone)]
+pub struct Op {
+    pub op: OpCode,
+    pub a: u8, pub b: u8, pub c: u8,
+    pub d: u16
+}
+
+pub trait FromOp {
+    fn from_op(&Op) -> Self;
+}
+
 pub fn from_instr<A: FromInstr>(i: &Instr) -> A {
     FromInstr::from_instr(*i)
 }
@@ -62,11 +76,48 @@ impl FromInstr for OpAD {
 }
 
 impl Decode for Instr {
-    fn decode(self) -> OpCode {
+    fn decode(self) -> Result<OpCode, u8> {
         let opcode = from_instr::<OpABC>(&self).op;
         match FromPrimitive::from_u8(opcode) {
-            Some(op) => op,
-            None => panic!("invalid opcode: {}", opcode)
+            Some(op) => Ok(op),
+            None => Err(opcode)
         }
     }
 }
+
+impl FromOp for Op {
+    fn from_op(op: &Op) -> Op {
+        *op
+    }
+}
+
+impl FromOp for OpABC {
+    fn from_op(op: &Op) -> OpABC {
+        OpABC { op: op.op as u8, a: op.a, b: op.b, c: op.c }
+    }
+}
+
+impl FromOp for OpAD {
+    fn from_op(op: &Op) -> OpAD {
+        OpAD { op: op.op as u8, a: op.a, d: op.d }
+    }
+}
+
+// Decodes every instruction, or returns the index and opcode of the first
+// invalid one.
+pub fn predecode(func: &[Instr]) -> Result<Vec<Op>, (InstrPtr, u8)> {
+    let mut ops = Vec::with_capacity(func.len());
+
+    for (ip, instr) in func.iter().enumerate() {
+        let op = match instr.decode() {
+            Ok(op) => op,
+            Err(opcode) => return Err((ip, opcode))
+        };
+        let abc = from_instr::<OpABC>(instr);
+        let ad = from_instr::<OpAD>(instr);
+
+        ops.push(Op { op: op, a: abc.a, b: abc.b, c: abc.c, d: ad.d });
+    }
+
+    Ok(ops)
+}
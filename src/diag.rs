@@ -10,6 +10,7 @@ use decode::Decode;
 use decode::from_instr;
 use decode::OpAD;
 use decode::OpABC;
+use decode::Op;
 
 impl Show for OpABC {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -27,9 +28,19 @@ impl Show for OpAD {
 
 impl Show for Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.decode().ty()  {
-            vm::TyAD =>  write!(f, "{}", from_instr::<OpAD>(self)),
-            vm::TyABC => write!(f, "{}", from_instr::<OpABC>(self))
+        match self.decode().map(|op| op.ty()) {
+            Ok(vm::TyAD) =>  write!(f, "{}", from_instr::<OpAD>(self)),
+            Ok(vm::TyABC) => write!(f, "{}", from_instr::<OpABC>(self)),
+            Err(opcode) => write!(f, "INVALID({})", opcode)
+        }
+    }
+}
+
+impl Show for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.op.ty() {
+            vm::TyAD =>  write!(f, "{}(a:{},d:{})", self.op, self.a, self.d),
+            vm::TyABC => write!(f, "{}(a:{},b:{},c:{})", self.op, self.a, self.b, self.c)
         }
     }
 }
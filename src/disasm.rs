@@ -0,0 +1,156 @@
+use std::io::IoResult;
+use std::collections::HashSet;
+
+use vm;
+use vm::Data;
+use vm::Code;
+use vm::DispatchData;
+use vm::InstrPtr;
+
+use decode::Decode;
+use decode::from_instr;
+use decode::{OpABC, OpAD};
+
+// Instruction indices that are jumped to, they get an `L<ip>:` label.
+pub fn jump_targets(code: &Code) -> HashSet<InstrPtr> {
+    let mut targets = HashSet::new();
+
+    for (ip, instr) in code.func.iter().enumerate() {
+        match instr.decode() {
+            Ok(vm::JUMP) | Ok(vm::JUMPF) | Ok(vm::JUMPT) => {
+                let target = ip as int + from_instr::<OpAD>(instr).d as i16 as int;
+                targets.insert(target as uint);
+            }
+            _ => ()
+        }
+    }
+
+    for h in code.handlers.iter() {
+        targets.insert(h.start);
+        targets.insert(h.end);
+        targets.insert(h.target);
+    }
+    targets
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.escape_default())
+}
+
+fn pooled<T>(pool: &Vec<T>, idx: u16, show: |&T| -> String) -> String {
+    match pool.as_slice().get(idx as uint) {
+        Some(val) => show(val),
+        None => format!("<invalid constant {}>", idx)
+    }
+}
+
+// The value of the constant, symbol, type or target an instruction refers
+// to, shown as a comment next to it.
+pub fn operand_comment(data: &Data, code: &Code, ip: InstrPtr) -> Option<String> {
+    let instr = &code.func[ip];
+    let abc = from_instr::<OpABC>(instr);
+    let ad = from_instr::<OpAD>(instr);
+
+    match instr.decode() {
+        Ok(vm::CSTR) | Ok(vm::NSGETS) =>
+            Some(pooled(&data.cstr, ad.d, |s| quote(s.as_slice()))),
+        Ok(vm::NSSETS) =>
+            Some(pooled(&data.cstr, abc.b as u16, |s| quote(s.as_slice()))),
+        Ok(vm::CKEY) =>
+            Some(pooled(&data.ckey, ad.d, |k| format!(":{}", k))),
+        Ok(vm::CINT) =>
+            Some(pooled(&data.cint, ad.d, |i| i.to_string())),
+        Ok(vm::CFLOAT) =>
+            Some(pooled(&data.cfloat, ad.d, |f| f.to_string())),
+        Ok(vm::CTYPE) =>
+            Some(pooled(&data.ctype, ad.d, |t| t.name.clone())),
+        Ok(vm::JUMP) | Ok(vm::JUMPF) | Ok(vm::JUMPT) =>
+            Some(format!("=> L{}", ip as int + ad.d as i16 as int)),
+        Ok(vm::FNEW) => match code.names.find(&(ad.d as uint)) {
+            Some(name) => Some(format!("=> fn{} {}", ad.d, name)),
+            None => Some(format!("=> fn{}", ad.d))
+        },
+        _ => None
+    }
+}
+
+pub fn disassemble<W: Writer>(w: &mut W, data: &Data, code: &Code,
+                              dd: &DispatchData) -> IoResult<()> {
+    let targets = jump_targets(code);
+    let mut location = None;
+
+    for (ip, instr) in code.func.iter().enumerate() {
+        match instr.decode() {
+            Ok(vm::FUNCF) | Ok(vm::FUNCV) => {
+                try!(writeln!(w, ""));
+                match code.names.find(&ip) {
+                    Some(name) => try!(writeln!(w, "fn{}:    ; {}", ip, name)),
+                    None => try!(writeln!(w, "fn{}:", ip))
+                }
+            }
+            _ => ()
+        }
+
+        let here = code.line_entry(ip).map(|e| e.location());
+        if here.is_some() && here != location {
+            try!(writeln!(w, "        ; {}", here.as_ref().unwrap()));
+        }
+        location = here;
+
+        if targets.contains(&ip) {
+            try!(writeln!(w, "L{}:", ip));
+        }
+
+        let text = instr.to_string();
+        match operand_comment(data, code, ip) {
+            Some(comment) => try!(writeln!(w, "{:>6}    {:<28} ; {}", ip, text, comment)),
+            None => try!(writeln!(w, "{:>6}    {}", ip, text))
+        }
+    }
+
+    if !dd.vtable.is_empty() {
+        try!(writeln!(w, ""));
+        try!(writeln!(w, "vtable:"));
+        for (vfunc, impls) in dd.vtable.iter() {
+            for (ty, func) in impls.iter() {
+                let name = match data.find_type(*ty) {
+                    Some(t) => t.name.clone(),
+                    None => ty.to_string()
+                };
+                try!(writeln!(w, "    {} {} => fn{}", vfunc, name, func));
+            }
+        }
+    }
+
+    if !code.handlers.is_empty() {
+        try!(writeln!(w, ""));
+        try!(writeln!(w, "handlers:"));
+        for h in code.handlers.iter() {
+            let ty = match h.catch_type {
+                Some(nr) => match data.find_type(nr) {
+                    Some(t) => t.name.clone(),
+                    None => nr.to_string()
+                },
+                None => "any".to_string()
+            };
+            try!(writeln!(w, "    L{}..L{} {} => L{} slot {}", h.start, h.end, ty,
+                          h.target, h.slot));
+        }
+    }
+
+    if !dd.arities.is_empty() {
+        try!(writeln!(w, ""));
+        try!(writeln!(w, "arities:"));
+        for (table, arities) in dd.arities.iter() {
+            for (nargs, func) in arities.fixed.iter() {
+                try!(writeln!(w, "    {} {} => fn{}", table, nargs, func));
+            }
+            match arities.variadic {
+                Some((nargs, func)) => try!(writeln!(w, "    {} {}+ => fn{}", table, nargs, func)),
+                None => ()
+            }
+        }
+    }
+
+    Ok(())
+}
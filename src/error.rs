@@ -0,0 +1,140 @@
+use std::fmt;
+
+use vm::Slot;
+use vm::OpCode;
+use vm::InstrPtr;
+use vm::Code;
+use vm::Context;
+
+#[deriving(Show, Clone)]
+pub enum ErrorKind {
+    InvalidOpcode(u8),
+    InvalidOperands,
+    SymbolNotFound(String),
+    NotAType,
+    NotAnObject,
+    NotAFunction,
+    NotAClosure,
+    NotAnArray,
+    NotASequence,
+    NotAnIndex,
+    IndexOutOfBounds(i64, uint),
+    DivideByZero,
+    IntegerOverflow,
+    FieldOutOfRange(String, uint),
+    ImmutableField(String, String),
+    NoVTableEntry(uint, uint),
+    // Number of arguments passed and the arity of the function.
+    ArityMismatch(i64, uint),
+    // A multi-arity function without a body for the number of arguments.
+    NoMatchingArity(uint, i64),
+    // The slot stack or the call depth hit its limit, with the depth.
+    StackOverflow(uint),
+    // An exception thrown by THROW that no handler caught.
+    Thrown,
+    // The verifier rejected the code, with its messages.
+    InvalidBytecode(Vec<String>),
+}
+
+// A runtime error raised by an instruction. `slots` holds the contents of the
+// slots the instruction was looking at when it failed.
+#[deriving(Clone)]
+pub struct VmError {
+    pub kind  : ErrorKind,
+    pub op    : Option<OpCode>,
+    pub ip    : InstrPtr,
+    pub slots : Vec<Slot>,
+    // The failing instruction and the CALLs of the active frames,
+    // innermost first. Symbolized only when the error is displayed.
+    pub backtrace : Vec<Context>,
+}
+
+// Shows a VmError with the function names and source locations of its
+// backtrace looked up in `code`.
+pub struct ErrorDisplay<'a> {
+    err  : &'a VmError,
+    code : &'a Code,
+}
+
+#[deriving(Clone)]
+pub struct StackFrame {
+    pub ip   : InstrPtr,
+    // Entry of the enclosing function, None outside of any function.
+    pub func : Option<InstrPtr>,
+    // From the function name table of the bytecode.
+    pub name : Option<String>,
+    // file:line:column from the line table of the bytecode.
+    pub source : Option<String>,
+}
+
+// Deep recursion has a lot of frames, only the ends are printed.
+static BACKTRACE_ENDS : uint = 10;
+
+pub type VmResult<T> = Result<T, VmError>;
+
+impl fmt::Show for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, self.func) {
+            (&Some(ref name), _) => try!(write!(f, "{}", name)),
+            (&None, Some(func)) => try!(write!(f, "fn{}", func)),
+            (&None, None) => try!(write!(f, "main"))
+        }
+        try!(write!(f, " at {}", self.ip));
+        match self.source {
+            Some(ref source) => write!(f, " ({})", source),
+            None => Ok(())
+        }
+    }
+}
+
+impl VmError {
+    pub fn stack_frames(&self, code: &Code) -> Vec<StackFrame> {
+        self.backtrace.iter().map(|ctx| code.stack_frame(ctx.ip)).collect()
+    }
+
+    pub fn display<'a>(&'a self, code: &'a Code) -> ErrorDisplay<'a> {
+        ErrorDisplay { err: self, code: code }
+    }
+
+    // The error without its backtrace.
+    pub fn message(&self) -> String {
+        let mut msg = match self.op {
+            Some(ref op) => format!("{} at {} ({})", self.kind, self.ip, op),
+            None => format!("{} at {}", self.kind, self.ip)
+        };
+        if !self.slots.is_empty() {
+            msg.push_str(format!(", slots: {}", self.slots).as_slice());
+        }
+        msg
+    }
+}
+
+fn fmt_frames<T: fmt::Show>(f: &mut fmt::Formatter, frames: &[T]) -> fmt::Result {
+    let n = frames.len();
+    for (i, frame) in frames.iter().enumerate() {
+        if n > 2 * BACKTRACE_ENDS && i >= BACKTRACE_ENDS && i < n - BACKTRACE_ENDS {
+            if i == BACKTRACE_ENDS {
+                try!(write!(f, "\n    ... {} more frames", n - 2 * BACKTRACE_ENDS));
+            }
+            continue;
+        }
+        try!(write!(f, "\n    in {}", frame));
+    }
+    Ok(())
+}
+
+// Without the code only the instruction indices of the backtrace are known.
+impl fmt::Show for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.message()));
+        let ips: Vec<InstrPtr> = self.backtrace.iter().map(|ctx| ctx.ip).collect();
+        fmt_frames(f, ips.as_slice())
+    }
+}
+
+impl<'a> fmt::Show for ErrorDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.err.message()));
+        fmt_frames(f, self.err.stack_frames(self.code).as_slice())
+    }
+}
@@ -0,0 +1,107 @@
+// Exceptions. THROW and every runtime error unwind to the innermost
+// handler of the code table covering the failing instruction, popping
+// frames off Vm.stack until one is found.
+//
+// A runtime error is thrown as an object of the type named VmError with the
+// fields kind, message and ip. A program that declares the type can catch
+// runtime errors by type, otherwise Vm::new adds it as a type of its own
+// and only handlers without a catch type see them.
+//
+// finally has no instruction of its own, a compiler covers the try body
+// with a handler without catch type that runs the finally code and throws
+// the exception again.
+
+use vm::Vm;
+use vm::Data;
+use vm::Slot;
+use vm::{Int, Str, Obj};
+use vm::{CljType, CljField};
+
+use error::{VmError, VmResult, Thrown};
+
+use decode::Op;
+use fetch::Fetch;
+
+static ERROR_TYPE : &'static str = "VmError";
+
+// The nr of the VmError type, adding it to the type table if the program
+// did not declare it. Called once by Vm::new.
+pub fn register_error_type(data: &mut Data) -> uint {
+    match data.ctype.iter().find(|t| t.name.as_slice() == ERROR_TYPE) {
+        Some(t) => return t.nr,
+        None => ()
+    }
+
+    let nr = data.ctype.iter().map(|t| t.nr + 1).max().unwrap_or(0);
+    let fields = ["kind", "message", "ip"].iter().enumerate().map(|(offset, name)| {
+        CljField { name: name.to_string(), offset: offset, mutable: false }
+    }).collect();
+
+    data.ctype.push(CljType { name: ERROR_TYPE.to_string(), nr: nr, size: 3,
+                              fields: fields });
+    nr
+}
+
+// The value a handler receives for `err`.
+fn exception_value(vm: &mut Vm, err: &VmError) -> Slot {
+    match err.kind {
+        Thrown => return err.slots[0].clone(),
+        _ => ()
+    }
+
+    let nr = vm.error_type;
+    let mut obj = vm.data.find_type(nr).unwrap().alloc();
+    {
+        let t = vm.data.find_type(nr).unwrap();
+        for field in t.fields.iter() {
+            let val = match field.name.as_slice() {
+                "kind" => Str(err.kind.to_string()),
+                "message" => Str(err.message()),
+                "ip" => Int(err.ip as i64),
+                _ => continue
+            };
+            // A declared VmError can have any layout, fields past its size
+            // are left out.
+            if field.offset < obj.fields.len() {
+                *obj.fields.get_mut(field.offset) = val;
+            }
+        }
+    }
+
+    Obj(vm.heap.alloc_object(obj))
+}
+
+// Unwinds to the handler for `err` and returns its first instruction, or
+// the error itself once every frame was popped without finding one.
+pub fn throw(vm: &mut Vm, err: VmError) -> VmResult<Op> {
+    let exc = exception_value(vm, &err);
+
+    loop {
+        let target = vm.code.handler(vm.code.ip, &exc).map(|h| (h.target, h.slot));
+        match target {
+            Some((target, slot)) => {
+                vm.slots.store(slot, exc);
+                vm.code.ip = target;
+                return Ok(vm.fetch(0));
+            }
+            None => ()
+        }
+
+        match vm.stack.pop() {
+            Some(caller) => {
+                vm.slots.truncate(2u);
+                vm.set_context(caller);
+            }
+            None => return Err(err)
+        }
+    }
+}
+
+// Whether a handler for `catch_type` catches `exc`, None catches everything.
+pub fn matches(catch_type: Option<uint>, exc: &Slot) -> bool {
+    match (catch_type, exc) {
+        (None, _) => true,
+        (Some(nr), &Obj(ref obj)) => obj.borrow().cljtype == nr,
+        _ => false
+    }
+}
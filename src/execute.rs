@@ -1,28 +1,45 @@
+use std::num::CheckedMul;
+
 use vm;
 use vm::Vm;
 use vm::Closure;
-use vm::{Nil, Int, Float, Bool, Str, Key, Func, VFunc, Obj, CType, SCC, Builtin};
-use vm::Instr;
+use vm::{Nil, Int, Float, Bool, Str, Key, Func, VFunc, MFunc, Obj, CType, SCC, Builtin, Array};
 use vm::Context;
 use vm::TopLevelBinding;
 
+use error::VmResult;
+use error::{InvalidOperands, SymbolNotFound, NotAType, NotAnObject,
+            NotAFunction, NotAClosure, NotAnArray, NotASequence, NotAnIndex,
+            IndexOutOfBounds, FieldOutOfRange, ImmutableField, NoVTableEntry,
+            ArityMismatch, NoMatchingArity, Thrown, DivideByZero,
+            IntegerOverflow};
+
 use fetch::Fetch;
-use decode::Decode;
-use decode::from_instr;
+use jit;
+use tracejit;
+use decode::{Op, FromOp};
 use decode::{OpABC, OpAD};
 
 pub trait Execute {
-    fn execute(&self, &mut Vm) -> Instr;
+    fn execute(&self, &mut Vm) -> VmResult<Op>;
 }
 
+// Returns a VmError for the instruction at the current ip from the
+// enclosing function, together with the slots it was looking at.
+macro_rules! vm_err (
+    ($vm:ident, $kind:expr $(, $slot:expr)*) => (
+        return Err($vm.error($kind, vec![$($slot.clone()),*]))
+    )
+)
+
 macro_rules! execute (
     (using $vm:ident with $args:ident
      $($op:pat as $ty:ty => $code:expr),+) => (
 
-        impl Execute for Instr {
-            fn execute(&self, $vm:&mut Vm) -> Instr {
-                match self.decode() {
-                    $($op => { let $args : $ty = from_instr(self); $code }),+
+        impl Execute for Op {
+            fn execute(&self, $vm:&mut Vm) -> VmResult<Op> {
+                match self.op {
+                    $($op => { let $args : $ty = FromOp::from_op(self); Ok($code) }),+
                 }
             }
         }
@@ -46,8 +63,9 @@ execute! {
         vm.fetch_next()
     },
 
+    // D is a signed 16 bit literal, like the offsets of the jumps.
     vm::CSHORT as OpAD => {
-        let integer = args.d as i64;
+        let integer = args.d as i16 as i64;
         vm.slots[args.a] = Int(integer);
         vm.fetch_next()
     },
@@ -102,7 +120,7 @@ execute! {
 
         let value = match vm.symbol_table.find(&symbol) {
             Some(toplvlbinging) => toplvlbinging.val.clone(),
-            None => panic!("Symbol not found in symbol_table")
+            None => vm_err!(vm, SymbolNotFound(symbol.clone()))
         };
 
         vm.slots[args.a] = value;
@@ -116,12 +134,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Int(val1 + val2),
-            (Float(val1), Float(val2)) => Float(val1 + val2),
-            (Int(val1),   Float(val2)) => Float(val1 as f64 + val2),
-            (Float(val1), Int(val2))   => Float(val1 + val2 as f64),
-            _ => panic!("Invalid operand types for ADDVV")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Int(val1 + val2),
+            (&Float(val1), &Float(val2)) => Float(val1 + val2),
+            (&Int(val1),   &Float(val2)) => Float(val1 as f64 + val2),
+            (&Float(val1), &Int(val2))   => Float(val1 + val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -133,12 +151,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Int(val1 - val2),
-            (Float(val1), Float(val2)) => Float(val1 - val2),
-            (Int(val1),   Float(val2)) => Float(val1 as f64 - val2),
-            (Float(val1), Int(val2))   => Float(val1 - val2 as f64),
-            _ => panic!("Invalid operand types for SUBVV")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Int(val1 - val2),
+            (&Float(val1), &Float(val2)) => Float(val1 - val2),
+            (&Int(val1),   &Float(val2)) => Float(val1 as f64 - val2),
+            (&Float(val1), &Int(val2))   => Float(val1 - val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -150,12 +168,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Int(val1 * val2),
-            (Float(val1), Float(val2)) => Float(val1 * val2),
-            (Int(val1),   Float(val2)) => Float(val1 as f64 * val2),
-            (Float(val1), Int(val2))   => Float(val1 * val2 as f64),
-            _ => panic!("Invalid operand types for MULVV")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Int(val1 * val2),
+            (&Float(val1), &Float(val2)) => Float(val1 * val2),
+            (&Int(val1),   &Float(val2)) => Float(val1 as f64 * val2),
+            (&Float(val1), &Int(val2))   => Float(val1 * val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -167,12 +185,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Float(val1 as f64 / val2 as f64),
-            (Float(val1), Float(val2)) => Float(val1 / val2),
-            (Int(val1),   Float(val2)) => Float(val1 as f64 / val2),
-            (Float(val1), Int(val2))   => Float(val1 / val2 as f64),
-            _ => panic!("Invalid operand types for DIVVV")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Float(val1 as f64 / val2 as f64),
+            (&Float(val1), &Float(val2)) => Float(val1 / val2),
+            (&Int(val1),   &Float(val2)) => Float(val1 as f64 / val2),
+            (&Float(val1), &Int(val2))   => Float(val1 / val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -184,12 +202,36 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Int(val1 % val2),
-            (Float(val1), Float(val2)) => Float(val1 % val2),
-            (Int(val1),   Float(val2)) => Float(val1 as f64 % val2),
-            (Float(val1), Int(val2))   => Float(val1 % val2 as f64),
-            _ => panic!("Invalid operand types for MOVVV")
+        let res = match (&slot1, &slot2) {
+            (&Int(_),      &Int(0))      => vm_err!(vm, DivideByZero, slot1, slot2),
+            (&Int(_),      &Int(-1))     => Int(0),
+            (&Int(val1),   &Int(val2))   => Int(val1 % val2),
+            (&Float(val1), &Float(val2)) => Float(val1 % val2),
+            (&Int(val1),   &Float(val2)) => Float(val1 as f64 % val2),
+            (&Float(val1), &Int(val2))   => Float(val1 % val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
+        };
+
+        vm.slots[args.a] = res;
+
+        vm.fetch_next()
+    },
+
+    vm::POWVV as OpABC => {
+        let slot1 = vm.slots.load(args.b);
+        let slot2 = vm.slots.load(args.c);
+
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2)) if val2 >= 0
+                                       => match checked_pow(val1, val2) {
+                                              Some(res) => Int(res),
+                                              None => vm_err!(vm, IntegerOverflow, slot1, slot2)
+                                          },
+            (&Int(val1),   &Int(val2))   => Float((val1 as f64).powf(val2 as f64)),
+            (&Float(val1), &Float(val2)) => Float(val1.powf(val2)),
+            (&Int(val1),   &Float(val2)) => Float((val1 as f64).powf(val2)),
+            (&Float(val1), &Int(val2))   => Float(val1.powf(val2 as f64)),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -204,12 +246,14 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 == val2),
-            (Float(val1), Float(val2)) => Bool(val1 == val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 == val2),
-            (Float(val1), Int(val2))   => Bool(val1 == val2 as f64),
-            _ => panic!("Invalid operand types for ISEQ")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 == val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 == val2),
+            (&Int(val1),   &Float(val2)) => Bool(val1 as f64 == val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 == val2 as f64),
+            (&Obj(_),      &Obj(_))      |
+            (&Array(_),    &Array(_))    => Bool(slot1.is_identical(&slot2)),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -220,45 +264,46 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 != val2),
-            (Float(val1), Float(val2)) => Bool(val1 != val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 != val2),
-            (Float(val1), Int(val2))   => Bool(val1 != val2 as f64),
-            _ => panic!("Invalid operand types for ISEQ")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 != val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 != val2),
+            (&Int(val1),   &Float(val2)) => Bool(val1 as f64 != val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 != val2 as f64),
+            (&Obj(_),      &Obj(_))      |
+            (&Array(_),    &Array(_))    => Bool(!slot1.is_identical(&slot2)),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
         vm.fetch_next()
     },
 
-    /*
     vm::ISLT as OpABC => {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 > val2),
-            (Float(val1), Float(val2)) => Bool(val1 > val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 > val2),
-            (Float(val1), Int(val2))   => Bool(val1 > val2 as f64),
-            _ => panic!("Invalid operand types for ISLT")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 < val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 < val2),
+            (&Int(val1),   &Float(val2)) => Bool((val1 as f64) < val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 < val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
-        vm.slots[args.a] = match res { true => false, false => true };
+        vm.slots[args.a] = res;
         vm.fetch_next()
-    },*/
+    },
 
     vm::ISGE as OpABC => {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 >= val2),
-            (Float(val1), Float(val2)) => Bool(val1 >= val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 >= val2),
-            (Float(val1), Int(val2))   => Bool(val1 >= val2 as f64),
-            _ => panic!("Invalid operand types for ISGE")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 >= val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 >= val2),
+            (&Int(val1),   &Float(val2)) => Bool(val1 as f64 >= val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 >= val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -269,12 +314,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 <= val2),
-            (Float(val1), Float(val2)) => Bool(val1 <= val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 <= val2),
-            (Float(val1), Int(val2))   => Bool(val1 <= val2 as f64),
-            _ => panic!("Invalid operand types for ISLE")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 <= val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 <= val2),
+            (&Int(val1),   &Float(val2)) => Bool(val1 as f64 <= val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 <= val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -285,12 +330,12 @@ execute! {
         let slot1 = vm.slots.load(args.b);
         let slot2 = vm.slots.load(args.c);
 
-        let res = match (slot1, slot2) {
-            (Int(val1),   Int(val2))   => Bool(val1 > val2),
-            (Float(val1), Float(val2)) => Bool(val1 > val2),
-            (Int(val1),   Float(val2)) => Bool(val1 as f64 > val2),
-            (Float(val1), Int(val2))   => Bool(val1 > val2 as f64),
-            _ => panic!("Invalid operand types for ISGT")
+        let res = match (&slot1, &slot2) {
+            (&Int(val1),   &Int(val2))   => Bool(val1 > val2),
+            (&Float(val1), &Float(val2)) => Bool(val1 > val2),
+            (&Int(val1),   &Float(val2)) => Bool(val1 as f64 > val2),
+            (&Float(val1), &Int(val2))   => Bool(val1 > val2 as f64),
+            _ => vm_err!(vm, InvalidOperands, slot1, slot2)
         };
 
         vm.slots[args.a] = res;
@@ -305,6 +350,7 @@ execute! {
   
         let new_fnew = match func_slot {
                         SCC(clos) => {SCC(Closure{func:args.d as uint,
+                                                  arities:None,
                                                   freevar:clos.freevar.clone()})
                                      }
                         _ =>  Func(args.d as uint),
@@ -321,6 +367,26 @@ execute! {
         vm.fetch_next()
     },
 
+//    OP     A    D
+//    MFNEW  dst  arities
+//    MFNEW puts the multi-arity function D of the arity table into dst,
+//    inside a closure it shares the free vars like FNEW.
+
+    vm::MFNEW as OpAD => {
+        let arities = args.d as uint;
+
+        let new_mfnew = match vm.slots.load(1u) {
+            SCC(clos) => SCC(Closure{func:0,
+                                     arities:Some(arities),
+                                     freevar:clos.freevar.clone()}),
+            _ => MFunc(arities),
+        };
+
+        vm.slots[args.a] = new_mfnew;
+
+        vm.fetch_next()
+    },
+
     // ---------------------- JUMPs ----------------------
 
     vm::JUMP as OpAD => {
@@ -352,11 +418,13 @@ execute! {
     // ---------------------- Unary Ops ----------------------
 
     vm::MOV as OpAD => {
+        try!(vm.reserve_slots(args.d as uint + 1));
         vm.slots[args.a] = vm.slots.load(args.d);
         vm.fetch_next()
     },
 
     vm::NOT as OpAD => {
+        try!(vm.reserve_slots(args.d as uint + 1));
         let src_slot = vm.slots.load(args.d);
 
         let dst_val = match src_slot {
@@ -369,12 +437,13 @@ execute! {
     },
 
     vm::NEG as OpAD => {
+        try!(vm.reserve_slots(args.d as uint + 1));
         let src_slot = vm.slots.load(args.d);
 
         let dst_val = match src_slot {
             Int(val)   => Int(- val),
             Float(val) => Float(- val),
-            _ => panic!("Tried to execute invalid bytecode, NOT")
+            ref slot => vm_err!(vm, InvalidOperands, slot)
         };
         vm.slots.store(args.a, dst_val);
         vm.fetch_next()
@@ -394,7 +463,10 @@ execute! {
     },
 
     vm::LOOP as OpABC => {
-        vm.fetch_next()
+        match tracejit::loop_hit(vm) {
+            Some(next) => next,
+            None => jit::hot_point(vm)
+        }
     },
 
     // ---------------------- Function Calls ----------------------
@@ -402,58 +474,64 @@ execute! {
     vm::CALL as OpAD  => {
         let base = args.a as uint;
         let lit = args.d as i64;
-        
-        vm.slots[base] = Int(lit);
-
-        let func = match vm.slots.load(base+1) {
-            VFunc(vfunc) => {let type_int = match vm.slots.load(base+2) {
-                                Obj(val)  => val.cljtype,
-                                ref slot => panic!("Stack not ready, base+2 
-                                                   is not of type CType: {}", slot)
-                             };
-                             vm.dd.vtable[vfunc][type_int] }
-            Func(func)   => func,
-            SCC(clos)    => clos.func,
-            Builtin(_)   => -1,
-            ref slot     => panic!("Tried to execute invalid function 2: {}", slot)
-        };
 
-        let old = vm.get_context();
-        vm.stack.push(old);
+        try!(call(vm, base, lit))
+    },
+
+//    OP     A     D
+//    TCALL  base  nargs(lit)
+//    TCALL calls like CALL but replaces the current frame, the callee and
+//    its arguments are moved down to the frame base and no context is
+//    pushed, the callee returns directly to our caller.
+
+    vm::TCALL as OpAD => {
+        let base = args.a as uint;
+        let lit = args.d as i64;
+
+        try!(tail_call(vm, base, lit))
+    },
 
-        let newbase = vm.slots.base + base;
-        vm.set_context(Context {
-            base : newbase,
-            ip : func,
-        });
+//    OP     A     B         C
+//    APPLY  base  seq(var)  nargs(lit)
+//    APPLY calls the function in base+1 with the C arguments already in
+//    base+2.. followed by the elements of the sequence in B.
 
- 
-        match vm.slots.load(base+1) {
-                Builtin(f) => { f(vm);
+    vm::APPLY as OpABC => {
+        let base = args.a as uint;
+        let nargs = args.c as uint;
 
-                                for i in range(2, args.a as int + 10) {
-                                    vm.slots.store(i, Nil);
-                                }
+        let spread = match vm.slots.load(args.b as uint) {
+            Array(arr) => arr.borrow().clone(),
+            Nil => vec![],
+            ref slot => vm_err!(vm, NotASequence, slot)
+        };
 
-                                let caller = vm.stack.pop().unwrap();
-                                vm.set_context(caller);
+        let end = vm.slots.base + base + 2 + nargs + spread.len();
+        try!(vm.grow_slots(end));
 
-                                vm.fetch_next()
-                               }
-                _ => vm.fetch(0)
+        for (i, val) in spread.iter().enumerate() {
+            vm.slots.store(base + 2 + nargs + i, val.clone());
         }
+
+        try!(call(vm, base, (nargs + spread.len()) as i64))
+    },
+
+//    OP     A
+//    THROW  var
+//    THROW throws the value in A, see exception.rs.
+
+    vm::THROW as OpAD => {
+        vm_err!(vm, Thrown, vm.slots[args.a])
     },
 
     vm::RET as OpAD => {
-        vm.slots[0u] = vm.slots.load(args.a);
-        
-        //should use tranc
-        for i in range(2, args.a as int + 10) {
-            vm.slots.store(i, Nil);
-        }
-    
+        let caller = match vm.stack.pop() {
+            Some(caller) => caller,
+            None => vm_err!(vm, InvalidOperands, vm.slots[args.a])
+        };
 
-        let caller = vm.stack.pop().unwrap();
+        vm.slots[0u] = vm.slots.load(args.a);
+        vm.slots.truncate(2u);
         vm.set_context(caller);
 
         vm.fetch_next()
@@ -461,12 +539,52 @@ execute! {
 
     // --------------- Function Def ------------
 
+//    OP     A
+//    FUNCF  arity(lit)
+//    FUNCF checks that the function was called with exactly A arguments.
+
     vm::FUNCF as OpAD => {
-        vm.fetch_next()
+        if !vm.stack.is_empty() {
+            match vm.slots.load(0u) {
+                Int(nargs) if nargs == args.a as i64 => (),
+                Int(nargs) => vm_err!(vm, ArityMismatch(nargs, args.a as uint)),
+                ref slot => vm_err!(vm, InvalidOperands, slot)
+            }
+        }
+
+        jit::hot_point(vm)
     },
 
+//    OP     A
+//    FUNCV  fixed(lit)
+//    FUNCV checks that the function was called with at least A arguments and
+//    packs the arguments after the first A into an array in slot 2+A, or Nil
+//    if there are none, like `& rest` in Clojure.
+
     vm::FUNCV as OpAD => {
-        vm.fetch_next()
+        if !vm.stack.is_empty() {
+            let fixed = args.a as uint;
+            let nargs = match vm.slots.load(0u) {
+                Int(nargs) if nargs >= fixed as i64 => nargs as uint,
+                Int(nargs) => vm_err!(vm, ArityMismatch(nargs, fixed)),
+                ref slot => vm_err!(vm, InvalidOperands, slot)
+            };
+
+            let start = 2 + fixed;
+            try!(vm.reserve_slots(2 + nargs));
+            let rest = if nargs > fixed {
+                let elems = vm.slots[start..start + nargs - fixed].to_vec();
+                for i in range(start + 1, start + nargs - fixed) {
+                    vm.slots.store(i, Nil);
+                }
+                Array(vm.heap.alloc_array(elems))
+            } else {
+                Nil
+            };
+            vm.slots.store(start, rest);
+        }
+
+        jit::hot_point(vm)
     },
 
     // ---------------- Types ------------------
@@ -476,20 +594,27 @@ execute! {
 //    ALLOC allocates the empty instance of Type D and puts a reference to into dst.
 
     vm::ALLOC as OpAD => {
+        try!(vm.reserve_slots(args.d as uint + 1));
         let index = match vm.slots.load(args.d as uint) {
             CType(index) => index as uint,
-            _ => panic!("ALLOC Failed!")
+            ref slot => vm_err!(vm, NotAType, slot)
         };
 
-        let t = vm.data.ctype[index].clone();
+        let t = match vm.data.ctype.as_slice().get(index) {
+            Some(t) => t.clone(),
+            None => vm_err!(vm, NotAType, CType(index))
+        };
 
-        vm.slots.store(args.a as uint, Obj(t.alloc()) );
+        let obj = vm.heap.alloc_object(t.alloc());
+        vm.slots.store(args.a as uint, Obj(obj) );
 
         vm.fetch_next()
     },
 
 //    OP        A    B            C
 //    SETFIELD  ref  offset(lit)  var
+//    SETFIELD only writes fields declared mutable, use INITFIELD in the
+//    constructor sequence to set the immutable ones.
 
     vm::SETFIELD as OpABC => {
         let var = vm.slots.load(args.c as uint);
@@ -498,17 +623,68 @@ execute! {
 
         let ref_index = args.a as uint;
 
-        let obj = vm.slots.load(ref_index).clone();  
+        let obj = match vm.slots.load(ref_index) {
+            Obj(sobj) =>  sobj,
+            ref slot => vm_err!(vm, NotAnObject, slot)
+        };
+
+        let cljtype = obj.borrow().cljtype;
+        let (type_name, field) = match vm.data.find_type(cljtype) {
+            Some(t) => (t.name.clone(),
+                        t.fields.iter().find(|f| f.offset == offset).map(|f| f.clone())),
+            None => vm_err!(vm, NotAType, CType(cljtype))
+        };
+
+        match field {
+            Some(ref f) if f.mutable && offset < obj.borrow().fields.len() => (),
+            Some(ref f) if f.mutable =>
+                vm_err!(vm, FieldOutOfRange(type_name, offset), Obj(obj.clone()), var),
+            Some(f) => vm_err!(vm, ImmutableField(type_name, f.name), Obj(obj), var),
+            None => vm_err!(vm, FieldOutOfRange(type_name, offset), Obj(obj), var)
+        }
+
+        *obj.borrow_mut().fields.get_mut(offset) = var;
+
+        vm.fetch_next()
+    },
 
-        let mut inside_obj = match obj {
+//    OP         A    B            C
+//    INITFIELD  ref  offset(lit)  var
+//    INITFIELD writes a field regardless of its mutability, it is emitted by
+//    constructors between ALLOC and the first use of the object. Each field
+//    is initialized once, an immutable one cannot be written again.
+
+    vm::INITFIELD as OpABC => {
+        let var = vm.slots.load(args.c as uint);
+
+        let offset = args.b as uint;
+
+        let obj = match vm.slots.load(args.a as uint) {
             Obj(sobj) =>  sobj,
-            _ => panic!("SETFIELD Failed!")
+            ref slot => vm_err!(vm, NotAnObject, slot)
+        };
+
+        let cljtype = obj.borrow().cljtype;
+        let (type_name, field) = match vm.data.find_type(cljtype) {
+            Some(t) => (t.name.clone(),
+                        t.fields.iter().find(|f| f.offset == offset).map(|f| f.clone())),
+            None => vm_err!(vm, NotAType, CType(cljtype))
         };
-        
-        *inside_obj.fields.get_mut(offset) = var;
-        
-        let myobj = inside_obj;
-        vm.slots.store(ref_index, Obj(myobj) );
+
+        if offset >= obj.borrow().fields.len() {
+            vm_err!(vm, FieldOutOfRange(type_name, offset), Obj(obj), var)
+        }
+        match field {
+            Some(ref f) if !f.mutable && obj.borrow().initialized[offset] =>
+                vm_err!(vm, ImmutableField(type_name, f.name.clone()), Obj(obj.clone()), var),
+            _ => ()
+        }
+
+        {
+            let mut obj = obj.borrow_mut();
+            *obj.fields.get_mut(offset) = var;
+            *obj.initialized.get_mut(offset) = true;
+        }
 
         vm.fetch_next()
     },
@@ -524,17 +700,97 @@ execute! {
 
         let refr = match slot_refr {
             Obj(sobj) => sobj,
-            _ => panic!("GETFIELD Failed!")
+            ref slot => vm_err!(vm, NotAnObject, slot)
         };
 
         let offset = args.c as uint;
-        let dst = refr.fields[offset].clone();
+        let field = refr.borrow().fields.as_slice().get(offset).map(|f| f.clone());
+        let dst = match field {
+            Some(dst) => dst,
+            None => {
+                let cljtype = refr.borrow().cljtype;
+                let type_name = match vm.data.find_type(cljtype) {
+                    Some(t) => t.name.clone(),
+                    None => vm_err!(vm, NotAType, CType(cljtype))
+                };
+                vm_err!(vm, FieldOutOfRange(type_name, offset), Obj(refr))
+            }
+        };
         
         vm.slots.store(dst_index, dst);
 
         vm.fetch_next()
     },
 
+    // ---------------- Arrays ------------------
+
+//    OP        A    B      C
+//    NEWARRAY  dst  start  count(lit)
+//    NEWARRAY creates an array of the C slots starting at B and puts a
+//    reference to it into dst.
+
+    vm::NEWARRAY as OpABC => {
+        let start = args.b as uint;
+        let count = args.c as uint;
+
+        let elems = vm.slots[start..start+count].to_vec();
+
+        let arr = vm.heap.alloc_array(elems);
+        vm.slots.store(args.a as uint, Array(arr));
+
+        vm.fetch_next()
+    },
+
+//    OP        A    B      C
+//    GETARRAY  dst  array  index(var)
+
+    vm::GETARRAY as OpABC => {
+        let index = match vm.slots.load(args.c as uint) {
+            Int(index) => index,
+            ref slot => vm_err!(vm, NotAnIndex, slot)
+        };
+
+        let val = match vm.slots.load(args.b as uint) {
+            Array(arr) => {
+                let arr = arr.borrow();
+                if index < 0 || index as uint >= arr.len() {
+                    vm_err!(vm, IndexOutOfBounds(index, arr.len()))
+                }
+                arr[index as uint].clone()
+            }
+            ref slot => vm_err!(vm, NotAnArray, slot)
+        };
+
+        vm.slots.store(args.a as uint, val);
+
+        vm.fetch_next()
+    },
+
+//    OP        A      B           C
+//    SETARRAY  array  index(var)  var
+
+    vm::SETARRAY as OpABC => {
+        let index = match vm.slots.load(args.b as uint) {
+            Int(index) => index,
+            ref slot => vm_err!(vm, NotAnIndex, slot)
+        };
+
+        let var = vm.slots.load(args.c as uint);
+
+        match vm.slots.load(args.a as uint) {
+            Array(arr) => {
+                let mut arr = arr.borrow_mut();
+                if index < 0 || index as uint >= arr.len() {
+                    vm_err!(vm, IndexOutOfBounds(index, arr.len()))
+                }
+                *arr.get_mut(index as uint) = var;
+            }
+            ref slot => vm_err!(vm, NotAnArray, slot)
+        };
+
+        vm.fetch_next()
+    },
+
     // -------------------- Closure -------------------
 
     vm::UCLO as OpAD => {
@@ -543,17 +799,27 @@ execute! {
         let end_slot = args.d as uint;
         let fnew_slot_index = end_slot + 1;
 
+        if end_slot < start_slot {
+            vm_err!(vm, InvalidOperands);
+        }
+        try!(vm.reserve_slots(fnew_slot_index + 1));
+
         let new_freevars = vm.slots[start_slot..end_slot+1].to_vec();
 
         match vm.slots.load(fnew_slot_index) {
             Func(func) => {vm.slots.store(fnew_slot_index, SCC(Closure{func:func,
+                                                                       arities:None,
                                                                        freevar:new_freevars}));
                           }
+            MFunc(arities) => {vm.slots.store(fnew_slot_index, SCC(Closure{func:0,
+                                                                           arities:Some(arities),
+                                                                           freevar:new_freevars}));
+                              }
             SCC(clos) =>  {let mut new_clos = clos.clone();
                            new_clos.freevar.push_all(vm.slots[start_slot..end_slot+1]);
                            vm.slots.store(fnew_slot_index, SCC( new_clos.clone()));
                           }
-            _ => panic!("Not Func type on FNEW slot")             
+            ref slot => vm_err!(vm, NotAFunction, slot)
         };
       
         vm.fetch_next()
@@ -568,8 +834,11 @@ execute! {
         let dst_idx = args.a as uint;
 
         let freevar = match vm.slots.load(1u) {
-                        SCC(clos) => clos.freevar[idx].clone(),
-                        _ => panic!("Not Closure in Slot") 
+                        SCC(clos) => match clos.freevar.as_slice().get(idx) {
+                            Some(freevar) => freevar.clone(),
+                            None => vm_err!(vm, InvalidOperands, SCC(clos.clone()))
+                        },
+                        ref slot => vm_err!(vm, NotAClosure, slot)
                       };
 
 
@@ -582,6 +851,7 @@ execute! {
     // --------------- Run-Time Behavior ------------
 
     vm::DROP as OpAD => {
+        try!(vm.reserve_slots(args.d as uint + 1));
 
         for i in range(args.a as int, 1 + args.d as int) {
             vm.slots.store(i, Nil);
@@ -590,20 +860,144 @@ execute! {
         vm.fetch_next()
     },
 
-    // Currently very very slow
     vm::TRANC as OpAD => {
-
-        for i in range(args.d as uint, vm.slots.slot.len() ) {
-            vm.slots.store(i, Nil);
-        }
-
+        vm.slots.truncate(args.d);
         vm.fetch_next()
     },
 
 
-    _ as Instr => {
+    _ as Op => {
         error!("skipping instruction: {}", args);
         vm.fetch_next()
     }
 }
 
+// The entry of the body of multi-arity function `arities` that takes
+// `nargs` arguments.
+fn select_arity(vm: &Vm, arities: uint, nargs: i64) -> VmResult<uint> {
+    match vm.dd.arities.find(&arities).and_then(|a| a.select(nargs as uint)) {
+        Some(entry) => Ok(entry),
+        None => vm_err!(vm, NoMatchingArity(arities, nargs))
+    }
+}
+
+// The entry point of the function in base+1 for a call with `nargs`
+// arguments, -1 for builtins.
+fn callee_entry(vm: &Vm, base: uint, nargs: i64) -> VmResult<uint> {
+    Ok(match vm.slots.load(base+1) {
+        VFunc(vfunc) => {let type_int = match vm.slots.load(base+2) {
+                            Obj(val)  => val.borrow().cljtype,
+                            ref slot => vm_err!(vm, NotAnObject, slot)
+                         };
+                         match vm.dd.vtable.find(&vfunc)
+                                 .and_then(|t| t.find(&type_int)) {
+                             Some(func) => *func,
+                             None => vm_err!(vm, NoVTableEntry(vfunc, type_int))
+                         } }
+        Func(func)   => func,
+        MFunc(arities) => try!(select_arity(vm, arities, nargs)),
+        SCC(clos)    => match clos.arities {
+                            Some(arities) => try!(select_arity(vm, arities, nargs)),
+                            None => clos.func
+                        },
+        Builtin(_)   => -1,
+        ref slot     => vm_err!(vm, NotAFunction, slot)
+    })
+}
+
+// Shared by CALL and APPLY, the callee is in base+1 and the nargs arguments
+// are in base+2.. of the current frame.
+fn call(vm: &mut Vm, base: uint, nargs: i64) -> VmResult<Op> {
+    try!(vm.reserve_slots(base + 2 + nargs as uint));
+    vm.slots[base] = Int(nargs);
+
+    let func = try!(callee_entry(vm, base, nargs));
+
+    let newbase = vm.slots.base + base;
+    try!(vm.enter_frame(newbase));
+
+    let old = vm.get_context();
+    vm.stack.push(old);
+
+    vm.set_context(Context {
+        base : newbase,
+        ip : func,
+    });
+
+    match vm.slots.load(1u) {
+            Builtin(f) => { f(vm);
+
+                            vm.slots.truncate(2u);
+
+                            let caller = match vm.stack.pop() {
+                                Some(caller) => caller,
+                                None => vm_err!(vm, InvalidOperands)
+                            };
+                            vm.set_context(caller);
+
+                            Ok(vm.fetch_next())
+                           }
+            _ => Ok(vm.fetch(0))
+    }
+}
+
+fn tail_call(vm: &mut Vm, base: uint, nargs: i64) -> VmResult<Op> {
+    // Outside of any function there is no frame to replace.
+    if vm.stack.is_empty() {
+        return call(vm, base, nargs);
+    }
+
+    try!(vm.reserve_slots(base + 2 + nargs as uint));
+    let func = try!(callee_entry(vm, base, nargs));
+
+    let n = nargs as uint;
+    for i in range(1, n + 2) {
+        let val = vm.slots.load(base + i);
+        vm.slots.store(i, val);
+    }
+    vm.slots.truncate(n + 2);
+    vm.slots[0u] = Int(nargs);
+
+    match vm.slots.load(1u) {
+        Builtin(f) => {
+            f(vm);
+
+            vm.slots.truncate(2u);
+
+            let caller = match vm.stack.pop() {
+                Some(caller) => caller,
+                None => vm_err!(vm, InvalidOperands)
+            };
+            vm.set_context(caller);
+
+            Ok(vm.fetch_next())
+        }
+        _ => {
+            vm.code.ip = func;
+            Ok(vm.fetch(0))
+        }
+    }
+}
+
+// Integer exponentiation by squaring, None when the result overflows.
+fn checked_pow(base: i64, exp: i64) -> Option<i64> {
+    let (mut acc, mut base, mut exp) = (1i64, base, exp);
+
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = match acc.checked_mul(&base) {
+                Some(acc) => acc,
+                None => return None
+            };
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = match base.checked_mul(&base) {
+                Some(base) => base,
+                None => return None
+            };
+        }
+    }
+
+    Some(acc)
+}
@@ -1,19 +1,36 @@
 use vm::Vm;
-use vm::Instr;
+use decode::Op;
 
 pub trait Fetch {
-    fn fetch(&mut self, offset: int) -> Instr;
-    fn fetch_next(&mut self) -> Instr;
+    fn fetch(&mut self, offset: int) -> Op;
+    fn fetch_next(&mut self) -> Op;
 }
 
 impl Fetch for Vm {
-    fn fetch(&mut self, offset: int) -> Instr {
+    #[cfg(not(feature = "decode-each"))]
+    fn fetch(&mut self, offset: int) -> Op {
         let new_ip = self.code.ip as int + offset;
         self.code.ip = new_ip as uint;
-        self.code.func[self.code.ip]
+        self.code.ops[self.code.ip]
     }
 
-    fn fetch_next(&mut self) -> Instr {
+    // The loop as it was before the code was decoded when loading, every
+    // fetch decodes the raw instruction again. Only for comparing the two
+    // with `cargo bench --features decode-each`.
+    #[cfg(feature = "decode-each")]
+    fn fetch(&mut self, offset: int) -> Op {
+        use decode::{Decode, OpABC, OpAD, from_instr};
+
+        let new_ip = self.code.ip as int + offset;
+        self.code.ip = new_ip as uint;
+
+        let instr = self.code.func[self.code.ip];
+        let abc = from_instr::<OpABC>(&instr);
+        let ad = from_instr::<OpAD>(&instr);
+        Op { op: instr.decode().unwrap(), a: abc.a, b: abc.b, c: abc.c, d: ad.d }
+    }
+
+    fn fetch_next(&mut self) -> Op {
         self.fetch(1)
     }
 }
@@ -0,0 +1,204 @@
+use std::rc;
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::collections::{HashSet, HashMap};
+
+use vm::Vm;
+use vm::Slot;
+use vm::{CljArray, CljObject, CljObjectRef};
+use vm::{Array, Obj, SCC};
+
+// Every array and object is registered with the heap when it is allocated.
+// Values are still reference counted, so acyclic garbage is freed as soon as
+// the last slot drops it. The collector traces from the roots and empties
+// every registered value it did not reach, which breaks the cycles reference
+// counting cannot free. A value the trace missed but something outside the
+// heap still holds, a builtin's local or a closure the roots do not see,
+// keeps everything it reaches alive.
+pub struct Heap {
+    arrays    : Vec<Weak<RefCell<Vec<Slot>>>>,
+    objects   : Vec<Weak<RefCell<CljObject>>>,
+    allocated : uint,
+    pub collections : uint,
+}
+
+pub struct GcStats {
+    pub live  : uint,
+    pub freed : uint,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            arrays    : vec![],
+            objects   : vec![],
+            allocated : 0,
+            collections : 0,
+        }
+    }
+
+    pub fn alloc_array(&mut self, elems: Vec<Slot>) -> CljArray {
+        let arr = Rc::new(RefCell::new(elems));
+        self.arrays.push(arr.downgrade());
+        self.allocated += 1;
+        arr
+    }
+
+    pub fn alloc_object(&mut self, obj: CljObject) -> CljObjectRef {
+        let obj = Rc::new(RefCell::new(obj));
+        self.objects.push(obj.downgrade());
+        self.allocated += 1;
+        obj
+    }
+
+    // Number of allocations since the last collection.
+    pub fn allocated(&self) -> uint {
+        self.allocated
+    }
+}
+
+fn addr<T>(rc: &Rc<T>) -> uint {
+    &**rc as *const T as uint
+}
+
+// Marks everything reachable from `slot`, with an explicit worklist so
+// deeply nested values do not overflow the native stack.
+fn mark(slot: &Slot, marked: &mut HashSet<uint>) {
+    let mut work = vec![slot.clone()];
+
+    loop {
+        let slot = match work.pop() {
+            Some(slot) => slot,
+            None => break
+        };
+
+        match slot {
+            Array(ref arr) => {
+                if marked.insert(addr(arr)) {
+                    work.push_all(arr.borrow().as_slice());
+                }
+            }
+            Obj(ref obj) => {
+                if marked.insert(addr(obj)) {
+                    work.push_all(obj.borrow().fields.as_slice());
+                }
+            }
+            SCC(ref clos) => work.push_all(clos.freevar.as_slice()),
+            _ => ()
+        }
+    }
+}
+
+// Counts the references of `slots` to the values in `unreached`. Closures
+// are stored by value, the references in their free vars belong to the
+// array or object holding the closure.
+fn count_refs(slots: &[Slot], unreached: &HashSet<uint>,
+              internal: &mut HashMap<uint, uint>) {
+    for slot in slots.iter() {
+        let a = match *slot {
+            Array(ref arr) => addr(arr),
+            Obj(ref obj) => addr(obj),
+            SCC(ref clos) => {
+                count_refs(clos.freevar.as_slice(), unreached, internal);
+                continue
+            }
+            _ => continue
+        };
+        if unreached.contains(&a) {
+            *internal.find_or_insert(a, 0) += 1;
+        }
+    }
+}
+
+// The roots are the slot stack, which holds the frames of every Context on
+// Vm.stack as well as the current one, the symbol table and, through the
+// closures stored there, their free vars. Values held outside of the roots
+// are found by their reference counts.
+pub fn collect(vm: &mut Vm) -> GcStats {
+    let mut marked = HashSet::new();
+
+    for slot in vm.slots.slot.iter() {
+        mark(slot, &mut marked);
+    }
+
+    for binding in vm.symbol_table.values() {
+        mark(&binding.val, &mut marked);
+    }
+
+    // Values not reached from the roots.
+    let mut unreached_arrays = vec![];
+    for weak in vm.heap.arrays.iter() {
+        match weak.upgrade() {
+            Some(ref arr) if marked.contains(&addr(arr)) => (),
+            Some(arr) => unreached_arrays.push(arr),
+            None => ()
+        }
+    }
+
+    let mut unreached_objects = vec![];
+    for weak in vm.heap.objects.iter() {
+        match weak.upgrade() {
+            Some(ref obj) if marked.contains(&addr(obj)) => (),
+            Some(obj) => unreached_objects.push(obj),
+            None => ()
+        }
+    }
+
+    // References among the unreached values. An unreached value with more
+    // strong references than that, less the one taken by upgrade above, is
+    // held from outside the heap.
+    let unreached: HashSet<uint> = unreached_arrays.iter().map(addr)
+        .chain(unreached_objects.iter().map(addr)).collect();
+    let mut internal = HashMap::new();
+    for arr in unreached_arrays.iter() {
+        count_refs(arr.borrow().as_slice(), &unreached, &mut internal);
+    }
+    for obj in unreached_objects.iter() {
+        count_refs(obj.borrow().fields.as_slice(), &unreached, &mut internal);
+    }
+
+    let mut held = vec![];
+    for arr in unreached_arrays.iter() {
+        if rc::strong_count(arr) - 1 > *internal.find(&addr(arr)).unwrap_or(&0) {
+            held.push(Array(arr.clone()));
+        }
+    }
+    for obj in unreached_objects.iter() {
+        if rc::strong_count(obj) - 1 > *internal.find(&addr(obj)).unwrap_or(&0) {
+            held.push(Obj(obj.clone()));
+        }
+    }
+    for slot in held.iter() {
+        mark(slot, &mut marked);
+    }
+
+    let mut freed = 0u;
+
+    let mut arrays = vec![];
+    for weak in vm.heap.arrays.iter() {
+        match weak.upgrade() {
+            Some(ref arr) if marked.contains(&addr(arr)) => arrays.push(weak.clone()),
+            Some(arr) => { arr.borrow_mut().clear(); freed += 1; }
+            None => ()
+        }
+    }
+
+    let mut objects = vec![];
+    for weak in vm.heap.objects.iter() {
+        match weak.upgrade() {
+            Some(ref obj) if marked.contains(&addr(obj)) => objects.push(weak.clone()),
+            Some(obj) => { obj.borrow_mut().fields.clear(); freed += 1; }
+            None => ()
+        }
+    }
+
+    vm.heap.arrays = arrays;
+    vm.heap.objects = objects;
+    vm.heap.allocated = 0;
+    vm.heap.collections += 1;
+
+    GcStats {
+        live  : vm.heap.arrays.len() + vm.heap.objects.len(),
+        freed : freed,
+    }
+}
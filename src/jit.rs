@@ -0,0 +1,427 @@
+// Baseline JIT. FUNCF, FUNCV and LOOP count how often a function is entered
+// or loops, once it is hot its bytecode is compiled to x86-64 and every
+// later hit runs the native code starting at the next instruction.
+//
+// Native code works on a copy of the frame where every slot is a (tag, val)
+// pair. Int, Bool and Nil slots are unboxed, everything else is tagged
+// OTHER. Instructions check the tags they depend on, like the interpreter
+// checks Slot types, and exit back to the interpreter at the instruction
+// when a check fails or the instruction is not supported. The exit returns
+// the instruction index to continue at, and the unboxed slots are written
+// back into the frame.
+
+use std::collections::HashMap;
+
+use vm;
+use vm::Vm;
+use vm::Code;
+use vm::Data;
+use vm::InstrPtr;
+use vm::{Slot, Nil, Int, Bool};
+
+use decode::Op;
+use fetch::Fetch;
+
+static TAG_NIL   : i8 = 0;
+static TAG_BOOL  : i8 = 1;
+static TAG_INT   : i8 = 2;
+static TAG_OTHER : i8 = 3;
+
+#[repr(C)]
+pub struct JitSlot {
+    tag : u64,
+    val : i64,
+}
+
+pub struct Compiled {
+    #[cfg(target_arch = "x86_64", target_os = "linux")]
+    mem : ::std::os::MemoryMap,
+    // Native offset of every instruction of the function.
+    offsets : HashMap<InstrPtr, uint>,
+    // Slots the function touches, the size of the native frame.
+    nslots : uint,
+}
+
+pub struct Jit {
+    // Hits per function, keyed by the FUNCF/FUNCV of the function.
+    counters : HashMap<InstrPtr, uint>,
+    // None if the function could not be compiled.
+    compiled : HashMap<InstrPtr, Option<Compiled>>,
+    // Function entry of every LOOP seen so far.
+    loop_entries : HashMap<InstrPtr, InstrPtr>,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit {
+            counters : HashMap::new(),
+            compiled : HashMap::new(),
+            loop_entries : HashMap::new(),
+        }
+    }
+}
+
+// The instructions of the function starting at `entry`, up to the next
+// function or the end of the code.
+fn function_end(code: &Code, entry: InstrPtr) -> InstrPtr {
+    range(entry + 1, code.ops.len()).find(|&ip| {
+        match code.ops[ip].op {
+            vm::FUNCF | vm::FUNCV => true,
+            _ => false
+        }
+    }).unwrap_or(code.ops.len())
+}
+
+fn frame_size(code: &Code, entry: InstrPtr, end: InstrPtr) -> uint {
+    let mut n = 0;
+    for ip in range(entry, end) {
+        let (read, wrote) = ::trace::effects(&code.func[ip]);
+        for &i in read.iter().chain(wrote.iter()) {
+            n = ::std::cmp::max(n, i + 1);
+        }
+    }
+    n
+}
+
+// D operands address up to 65535 slots, the displacements take a uint.
+fn tag_disp(slot: uint) -> i32 {
+    slot as i32 * 16
+}
+
+fn val_disp(slot: uint) -> i32 {
+    slot as i32 * 16 + 8
+}
+
+// Label of the stub returning to the interpreter at `ip`.
+#[cfg(target_arch = "x86_64", target_os = "linux")]
+fn exit(asm: &mut ::x64::Asm, exits: &mut HashMap<InstrPtr, ::x64::Label>,
+        ip: InstrPtr) -> ::x64::Label {
+    *exits.find_or_insert_with(ip, |_| asm.new_label())
+}
+
+#[cfg(target_arch = "x86_64", target_os = "linux")]
+fn compile(code: &Code, data: &Data, entry: InstrPtr) -> Option<Compiled> {
+    use std::os;
+    use std::ptr;
+    use libc::{c_void, size_t};
+    use libc::funcs::posix88::mman::mprotect;
+    use libc::consts::os::posix88::{PROT_READ, PROT_EXEC};
+    use x64::{Asm, Label, RAX, RCX};
+    use x64::{CondE, CondNE, CondL, CondGE, CondLE, CondG};
+
+    let end = function_end(code, entry);
+    let mut asm = Asm::new();
+
+    let labels: Vec<Label> = range(entry, end).map(|_| asm.new_label()).collect();
+    let mut offsets = HashMap::new();
+    let mut exits: HashMap<InstrPtr, Label> = HashMap::new();
+
+    for ip in range(entry, end) {
+        asm.bind(&labels[ip - entry]);
+        offsets.insert(ip, asm.pos());
+
+        let op: Op = code.ops[ip];
+        let (a, b, c, d) = (op.a as uint, op.b as uint, op.c as uint, op.d);
+
+        let target = |offset: u16| ip as int + offset as i16 as int;
+
+        match op.op {
+            vm::LOOP => (),
+
+            vm::CSHORT => {
+                asm.store_imm(val_disp(a), d as i16 as i32);
+                asm.store_imm(tag_disp(a), TAG_INT as i32);
+            },
+            vm::CINT => {
+                asm.mov_rax_imm64(data.cint[d as uint]);
+                asm.store(val_disp(a), RAX);
+                asm.store_imm(tag_disp(a), TAG_INT as i32);
+            },
+            vm::CBOOL => {
+                asm.store_imm(val_disp(a), (d == 1) as i32);
+                asm.store_imm(tag_disp(a), TAG_BOOL as i32);
+            },
+            vm::CNIL => {
+                asm.store_imm(val_disp(a), 0);
+                asm.store_imm(tag_disp(a), TAG_NIL as i32);
+            },
+
+            vm::MOV => {
+                let l = exit(&mut asm, &mut exits, ip);
+                asm.cmp_mem_imm(tag_disp(d as uint), TAG_OTHER);
+                asm.jcc(CondE, &l);
+                asm.load(RAX, tag_disp(d as uint));
+                asm.store(tag_disp(a), RAX);
+                asm.load(RAX, val_disp(d as uint));
+                asm.store(val_disp(a), RAX);
+            },
+
+            vm::ADDVV | vm::SUBVV | vm::MULVV |
+            vm::ISLT | vm::ISGE | vm::ISLE | vm::ISGT | vm::ISEQ | vm::ISNEQ => {
+                let l = exit(&mut asm, &mut exits, ip);
+                asm.cmp_mem_imm(tag_disp(b), TAG_INT);
+                asm.jcc(CondNE, &l);
+                asm.cmp_mem_imm(tag_disp(c), TAG_INT);
+                asm.jcc(CondNE, &l);
+                asm.load(RAX, val_disp(b));
+                asm.load(RCX, val_disp(c));
+
+                let tag = match op.op {
+                    vm::ADDVV => { asm.add_rax_rcx(); TAG_INT },
+                    vm::SUBVV => { asm.sub_rax_rcx(); TAG_INT },
+                    vm::MULVV => { asm.imul_rax_rcx(); TAG_INT },
+                    cmp => {
+                        asm.cmp_rax_rcx();
+                        asm.setcc_rax(match cmp {
+                            vm::ISLT => CondL,
+                            vm::ISGE => CondGE,
+                            vm::ISLE => CondLE,
+                            vm::ISGT => CondG,
+                            vm::ISEQ => CondE,
+                            _ => CondNE
+                        });
+                        TAG_BOOL
+                    }
+                };
+
+                asm.store(val_disp(a), RAX);
+                asm.store_imm(tag_disp(a), tag as i32);
+            },
+
+            vm::NEG => {
+                let l = exit(&mut asm, &mut exits, ip);
+                asm.cmp_mem_imm(tag_disp(d as uint), TAG_INT);
+                asm.jcc(CondNE, &l);
+                asm.load(RAX, val_disp(d as uint));
+                asm.neg_rax();
+                asm.store(val_disp(a), RAX);
+                asm.store_imm(tag_disp(a), TAG_INT as i32);
+            },
+
+            // Nil and false are falsy, every other value including the
+            // OTHER ones is truthy.
+            vm::NOT => {
+                let is_true = asm.new_label();
+                let is_false = asm.new_label();
+                let done = asm.new_label();
+                asm.load(RAX, tag_disp(d as uint));
+                asm.cmp_rax_imm(TAG_NIL);
+                asm.jcc(CondE, &is_true);
+                asm.cmp_rax_imm(TAG_BOOL);
+                asm.jcc(CondNE, &is_false);
+                asm.cmp_mem_imm(val_disp(d as uint), 0);
+                asm.jcc(CondE, &is_true);
+                asm.bind(&is_false);
+                asm.store_imm(val_disp(a), 0);
+                asm.jmp(&done);
+                asm.bind(&is_true);
+                asm.store_imm(val_disp(a), 1);
+                asm.bind(&done);
+                asm.store_imm(tag_disp(a), TAG_BOOL as i32);
+            },
+
+            vm::JUMP | vm::JUMPF | vm::JUMPT => {
+                let t = target(d);
+                let l = if t >= entry as int && t < end as int {
+                    labels[t as uint - entry]
+                } else {
+                    exit(&mut asm, &mut exits, t as uint)
+                };
+
+                match op.op {
+                    vm::JUMP => asm.jmp(&l),
+                    vm::JUMPF => {
+                        let next = asm.new_label();
+                        asm.load(RAX, tag_disp(a));
+                        asm.cmp_rax_imm(TAG_NIL);
+                        asm.jcc(CondE, &l);
+                        asm.cmp_rax_imm(TAG_BOOL);
+                        asm.jcc(CondNE, &next);
+                        asm.cmp_mem_imm(val_disp(a), 0);
+                        asm.jcc(CondE, &l);
+                        asm.bind(&next);
+                    },
+                    _ => {
+                        let next = asm.new_label();
+                        asm.load(RAX, tag_disp(a));
+                        asm.cmp_rax_imm(TAG_NIL);
+                        asm.jcc(CondE, &next);
+                        asm.cmp_rax_imm(TAG_BOOL);
+                        asm.jcc(CondNE, &l);
+                        asm.cmp_mem_imm(val_disp(a), 0);
+                        asm.jcc(CondNE, &l);
+                        asm.bind(&next);
+                    }
+                }
+            },
+
+            _ => {
+                let l = exit(&mut asm, &mut exits, ip);
+                asm.jmp(&l);
+            }
+        }
+    }
+
+    // Falling off the end continues in the interpreter.
+    asm.ret_imm(end as u32);
+
+    for (ip, label) in exits.iter() {
+        asm.bind(label);
+        asm.ret_imm(*ip as u32);
+    }
+
+    let bytes = asm.finish();
+
+    let mem = match os::MemoryMap::new(bytes.len(), &[os::MapReadable,
+                                                       os::MapWritable]) {
+        Ok(mem) => mem,
+        Err(err) => {
+            error!("jit: failed to map code memory: {}", err);
+            return None;
+        }
+    };
+
+    // Written while the mapping is RW, then switched to RX before it runs.
+    let protected = unsafe {
+        ptr::copy_nonoverlapping_memory(mem.data(), bytes.as_ptr(), bytes.len());
+        mprotect(mem.data() as *mut c_void, mem.len() as size_t,
+                 PROT_READ | PROT_EXEC)
+    };
+    if protected != 0 {
+        error!("jit: failed to protect code memory: {}", os::last_os_error());
+        return None;
+    }
+
+    Some(Compiled {
+        mem : mem,
+        offsets : offsets,
+        nslots : frame_size(code, entry, end),
+    })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn compile(_: &Code, _: &Data, _: InstrPtr) -> Option<Compiled> {
+    None
+}
+
+#[cfg(target_arch = "x86_64", not(target_os = "linux"))]
+fn compile(_: &Code, _: &Data, _: InstrPtr) -> Option<Compiled> {
+    None
+}
+
+fn to_jit(slot: &Slot) -> JitSlot {
+    match *slot {
+        Nil => JitSlot { tag: TAG_NIL as u64, val: 0 },
+        Bool(b) => JitSlot { tag: TAG_BOOL as u64, val: b as i64 },
+        Int(i) => JitSlot { tag: TAG_INT as u64, val: i },
+        _ => JitSlot { tag: TAG_OTHER as u64, val: 0 }
+    }
+}
+
+fn from_jit(slot: &JitSlot) -> Option<Slot> {
+    match slot.tag as i8 {
+        TAG_NIL => Some(Nil),
+        TAG_BOOL => Some(Bool(slot.val != 0)),
+        TAG_INT => Some(Int(slot.val)),
+        _ => None
+    }
+}
+
+// Runs compiled code starting at `ip`, returns where the interpreter has to
+// continue or None if there is no native code for `ip`.
+#[cfg(target_arch = "x86_64", target_os = "linux")]
+fn run_native(vm: &mut Vm, compiled: &Compiled, ip: InstrPtr) -> Option<InstrPtr> {
+    use std::mem;
+
+    let offset = match compiled.offsets.find(&ip) {
+        Some(offset) => *offset,
+        None => return None
+    };
+
+    let base = vm.slots.base;
+    if base + compiled.nslots > vm.slots.slot.len() {
+        return None;
+    }
+
+    let mut frame: Vec<JitSlot> = vm.slots.slot[base..base + compiled.nslots]
+                                    .iter().map(to_jit).collect();
+
+    let exit = unsafe {
+        let f: extern "C" fn(*mut JitSlot) -> u64 =
+            mem::transmute(compiled.mem.data().offset(offset as int));
+        f(frame.as_mut_ptr())
+    };
+
+    for (i, slot) in frame.iter().enumerate() {
+        match from_jit(slot) {
+            Some(val) => *vm.slots.slot.get_mut(base + i) = val,
+            None => ()
+        }
+    }
+    vm.slots.top = ::std::cmp::max(vm.slots.top, base + compiled.nslots);
+
+    Some(exit as InstrPtr)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn run_native(_: &mut Vm, _: &Compiled, _: InstrPtr) -> Option<InstrPtr> {
+    None
+}
+
+#[cfg(target_arch = "x86_64", not(target_os = "linux"))]
+fn run_native(_: &mut Vm, _: &Compiled, _: InstrPtr) -> Option<InstrPtr> {
+    None
+}
+
+// Called by FUNCF, FUNCV and LOOP once they are done, instead of fetching
+// the next instruction.
+pub fn hot_point(vm: &mut Vm) -> Op {
+    if !vm.opts.jit {
+        return vm.fetch_next();
+    }
+
+    let ip = vm.code.ip;
+    let entry = match vm.code.ops[ip].op {
+        vm::FUNCF | vm::FUNCV => ip,
+        _ => match vm.jit.loop_entries.find(&ip) {
+            Some(entry) => *entry,
+            None => {
+                let entry = vm.code.function_entry(ip).unwrap_or(0);
+                vm.jit.loop_entries.insert(ip, entry);
+                entry
+            }
+        }
+    };
+
+    let hits = {
+        let hits = vm.jit.counters.find_or_insert(entry, 0);
+        *hits += 1;
+        *hits
+    };
+
+    if hits == vm.opts.jit_threshold {
+        let compiled = compile(&vm.code, &vm.data, entry);
+        info!("jit: compiled fn{}: {}", entry, compiled.is_some());
+        vm.jit.compiled.insert(entry, compiled);
+    }
+
+    // Taken out of the table while it runs, the native code needs the Vm.
+    let compiled = match vm.jit.compiled.pop(&entry) {
+        Some(compiled) => compiled,
+        None => return vm.fetch_next()
+    };
+
+    let exit = match compiled {
+        Some(ref c) => run_native(vm, c, ip + 1),
+        None => None
+    };
+    vm.jit.compiled.insert(entry, compiled);
+
+    match exit {
+        Some(exit) => {
+            vm.code.ip = exit;
+            vm.fetch(0)
+        }
+        None => vm.fetch_next()
+    }
+}
@@ -0,0 +1,33 @@
+#![crate_name = "clojit"]
+#![crate_type = "lib"]
+
+#![feature(macro_rules)]
+#![feature(phase)]
+#![feature(slicing_syntax)]
+
+extern crate serialize;
+extern crate time;
+extern crate libc;
+#[phase(plugin, link)] extern crate log;
+
+pub mod vm;
+pub mod diag;
+pub mod decode;
+pub mod fetch;
+pub mod execute;
+pub mod builtin;
+pub mod error;
+pub mod gc;
+pub mod exception;
+pub mod binary;
+pub mod verify;
+pub mod load;
+pub mod disasm;
+pub mod asm;
+pub mod debug;
+pub mod trace;
+pub mod profile;
+pub mod jit;
+pub mod tracejit;
+#[cfg(target_arch = "x86_64", target_os = "linux")]
+pub mod x64;
@@ -0,0 +1,125 @@
+use std::io;
+
+use serialize::{json, Decoder, Decodable};
+
+use std::collections::HashMap;
+
+use vm;
+use vm::Data;
+use vm::CljType;
+use vm::DispatchData;
+use vm::Arities;
+use vm::Code;
+use vm::LineEntry;
+use vm::Handler;
+use vm::Keyword;
+use vm::Instr;
+use vm::OpCode;
+
+use decode;
+use decode::ToInstr;
+
+use binary;
+use asm;
+
+
+#[deriving(Decodable, Show, Clone)]
+struct JsonInstr {
+    op: OpCode,
+    a: Option<u8>,
+    b: Option<u8>,
+    c: Option<u8>,
+    d: Option<i32> // either i16 or u16
+}
+
+#[allow(non_snake_case)]
+#[deriving(Decodable, Show, Clone)]
+struct JsonBytecode {
+    bytecode  : Vec<Instr>,
+    CINT   : Vec<i64>,
+    CFLOAT : Vec<f64>,
+    CSTR   : Vec<String>,
+    CKEY   : Vec<Keyword>,
+    vtable : HashMap<uint,HashMap<uint, uint>>,
+    arities : Option<HashMap<uint, Arities>>,
+    names  : Option<HashMap<uint, String>>,
+    lines  : Option<Vec<LineEntry>>,
+    handlers : Option<Vec<Handler>>,
+    types  : Vec<CljType>
+}
+
+impl<D: Decoder<E>, E> Decodable<D, E> for Instr {
+    fn decode(dec: &mut D) -> Result<Instr, E> {
+        let json : JsonInstr = try!(Decodable::decode(dec));
+        match json.op.ty() {
+            vm::TyAD => {
+                Ok(decode::OpAD {
+                     op: json.op as u8,
+                      a: json.a.unwrap_or_default(),
+                      d: json.d.unwrap_or_default() as u16,
+                    }.to_instr())
+            },
+            vm::TyABC => {
+                Ok(decode::OpABC {
+                     op: json.op as u8,
+                      a: json.a.unwrap_or_default(),
+                      b: json.b.unwrap_or_default(),
+                      c: json.c.unwrap_or_default(),
+                    }.to_instr())
+            },
+        }
+    }
+}
+
+pub fn parse_json(path: &Path) -> Result<(Data, Code, DispatchData), json::DecoderError> {
+    
+    let mut reader = match io::File::open(path) {
+        Ok(reader) => reader,
+        Err(err) => return Err(json::ParseError(
+                               json::IoError(err.kind, err.desc)))
+    };
+
+    let mut decoder = match json::from_reader(&mut reader) {
+        Ok(json) => json::Decoder::new(json),
+        Err(err) => return Err(json::ParseError(err))
+    };
+
+    let bc : JsonBytecode = try!(Decodable::decode(&mut decoder));
+
+    let data = Data {
+        cint   : bc.CINT,
+        cfloat : bc.CFLOAT,
+        cstr   : bc.CSTR,
+        ckey   : bc.CKEY,
+        ctype  : bc.types
+    };
+
+    let dispatchdata  = DispatchData {
+        vtable : bc.vtable,
+        arities : bc.arities.unwrap_or(HashMap::new()),
+    };
+
+    let mut code = Code::new(bc.bytecode);
+    // Opcodes are decoded from their names, they are always valid.
+    code.decode().unwrap();
+    code.names = bc.names.unwrap_or(HashMap::new());
+    code.lines = bc.lines.unwrap_or(vec![]);
+    code.handlers = bc.handlers.unwrap_or(vec![]);
+
+    Ok((data, code, dispatchdata))
+}
+
+
+// Picks the loader by the magic bytes at the start of the file, `.asm`
+// files are assembled.
+pub fn load(path: &Path) -> Result<(Data, Code, DispatchData), String> {
+    if path.extension_str() == Some("asm") {
+        return asm::assemble_file(path);
+    }
+
+    match binary::is_binary(path) {
+        Ok(true) => binary::read(path).map_err(|err| err.to_string()),
+        Ok(false) => parse_json(path).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string())
+    }
+}
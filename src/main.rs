@@ -1,129 +1,259 @@
-#![feature(macro_rules)]
-#![feature(phase)]
-#![feature(slicing_syntax)]
-
-extern crate serialize;
-#[phase(plugin, link)] extern crate log;
-
+extern crate getopts;
+extern crate clojit;
 
 use std::io;
 use std::os;
 
-use serialize::{json, Decoder, Decodable};
+use getopts::{optopt, optflag, optmulti, getopts, usage};
 
-use vm::Vm;
-use vm::Data;
-use vm::CljType;
-use vm::DispatchData;
-use vm::Code;
-use vm::Keyword;
-use vm::Instr;
-use vm::OpCode;
+use clojit::vm::Vm;
+use clojit::vm::OpCode;
+use clojit::load;
+use clojit::binary;
+use clojit::verify;
+use clojit::debug::Debugger;
+use clojit::trace;
+use clojit::trace::Tracer;
+use clojit::profile::Profiler;
 
-use decode::ToInstr;
 
-use std::collections::HashMap;
+fn make_tracer(matches: &getopts::Matches, file: &str) -> Result<Tracer, String> {
+    let format = if matches.opt_present("trace-json") {
+        trace::JsonLines
+    } else {
+        trace::Text
+    };
 
-mod vm;
-mod diag;
-mod decode;
-mod fetch;
-mod execute;
-mod builtin;
+    let mut tracer = if file == "-" {
+        Tracer::stderr(format)
+    } else {
+        match io::File::create(&Path::new(file)) {
+            Ok(f) => Tracer::new(box io::BufferedWriter::new(f) as Box<Writer + 'static>,
+                                 format),
+            Err(err) => return Err(format!("{}: {}", file, err))
+        }
+    };
 
+    for name in matches.opt_strs("trace-op").iter() {
+        match OpCode::from_name(name.as_slice()) {
+            Some(op) => { tracer.ops.insert(op as u8); }
+            None => return Err(format!("unknown opcode {}", name))
+        }
+    }
 
-#[deriving(Decodable, Show, Clone)]
-struct JsonInstr {
-    op: OpCode,
-    a: Option<u8>,
-    b: Option<u8>,
-    c: Option<u8>,
-    d: Option<i32> // either i16 or u16
-}
+    match matches.opt_str("trace-range") {
+        Some(range) => {
+            let bounds: Vec<Option<uint>> = range.as_slice().split('-')
+                                                 .map(|n| from_str(n)).collect();
+            match bounds.as_slice() {
+                [Some(from), Some(to)] => tracer.range = Some((from, to)),
+                _ => return Err(format!("invalid --trace-range: {}", range))
+            }
+        }
+        None => ()
+    }
 
-#[allow(non_snake_case)]
-#[deriving(Decodable, Show, Clone)]
-struct JsonBytecode {
-    bytecode  : Vec<Instr>,
-    CINT   : Vec<i64>,
-    CFLOAT : Vec<f64>,
-    CSTR   : Vec<String>,
-    CKEY   : Vec<Keyword>,
-    vtable : HashMap<uint,HashMap<uint, uint>>,
-    types  : Vec<CljType>
+    Ok(tracer)
 }
 
-impl<D: Decoder<E>, E> Decodable<D, E> for Instr {
-    fn decode(dec: &mut D) -> Result<Instr, E> {
-        let json : JsonInstr = try!(Decodable::decode(dec));
-        match json.op.ty() {
-            vm::TyAD => {
-                Ok(decode::OpAD {
-                     op: json.op as u8,
-                      a: json.a.unwrap_or_default(),
-                      d: json.d.unwrap_or_default() as u16,
-                    }.to_instr())
-            },
-            vm::TyABC => {
-                Ok(decode::OpABC {
-                     op: json.op as u8,
-                      a: json.a.unwrap_or_default(),
-                      b: json.b.unwrap_or_default(),
-                      c: json.c.unwrap_or_default(),
-                    }.to_instr())
-            },
+fn main() {
+    let args = os::args();
+
+    let opts = [
+        optflag("h", "help", "print this help"),
+        optopt("o", "output", "write the program as binary bytecode to FILE instead of running it", "FILE"),
+        optopt("", "gc-threshold", "collect after N allocations, 0 disables the collector", "N"),
+        optflag("", "gc-report", "print a line for every garbage collection"),
+        optopt("", "max-slots", "raise a stack overflow when the slot stack grows past N slots", "N"),
+        optopt("", "max-depth", "raise a stack overflow past N nested calls", "N"),
+        optflag("d", "debug", "run in the interactive debugger"),
+        optmulti("b", "break", "set a debugger breakpoint on instruction IP", "IP"),
+        optflag("", "break-entry", "break on every function entry in the debugger"),
+        optopt("t", "trace", "trace every executed instruction to FILE, - for stderr", "FILE"),
+        optflag("", "trace-json", "write the trace as JSON lines"),
+        optmulti("", "trace-op", "only trace instructions with opcode OP", "OP"),
+        optopt("", "trace-range", "only trace instructions FROM to TO (exclusive)", "FROM-TO"),
+        optflag("p", "profile", "print instruction counts and time per function on exit"),
+        optopt("", "profile-folded", "write call stacks for flamegraph.pl to FILE on exit", "FILE"),
+        optflag("", "jit", "compile hot functions to native code"),
+        optopt("", "jit-threshold", "compile a function after N calls and loop iterations", "N"),
+        optflag("", "trace-jit", "record and run traces of hot loops"),
+        optopt("", "trace-jit-threshold", "record a loop after N iterations", "N"),
+    ];
+
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(err) => {
+            println!("{}", err);
+            os::set_exit_status(1);
+            return;
         }
+    };
+
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        println!("{}", usage(format!("usage: {} [options] input.json|input.asm|input.cljb",
+                                     args[0]).as_slice(), opts));
+        os::set_exit_status(1);
+        return;
     }
-}
 
-fn parse_json(path: &Path) -> Result<(Data, Code, DispatchData), json::DecoderError> {
-    
-    let mut reader = match io::File::open(path) {
-        Ok(reader) => reader,
-        Err(err) => return Err(json::ParseError(
-                               json::IoError(err.kind, err.desc)))
-    };
+    let path = Path::new(matches.free[0].as_slice());
 
-    let mut decoder = match json::from_reader(&mut reader) {
-        Ok(json) => json::Decoder::new(json),
-        Err(err) => return Err(json::ParseError(err))
+    let (data, code, dispatchdata) = match load::load(&path) {
+        Ok(program) => program,
+        Err(err) => {
+            println!("{}", err);
+            os::set_exit_status(1);
+            return;
+        }
     };
 
-    let bc : JsonBytecode = try!(Decodable::decode(&mut decoder));
+    match matches.opt_str("o") {
+        Some(out) => {
+            match binary::write(&Path::new(out.as_slice()), &data, &code, &dispatchdata) {
+                Ok(()) => (),
+                Err(err) => {
+                    println!("{}", err);
+                    os::set_exit_status(1);
+                }
+            }
+            return;
+        }
+        None => ()
+    }
 
-    let data = Data {
-        cint   : bc.CINT,
-        cfloat : bc.CFLOAT,
-        cstr   : bc.CSTR,
-        ckey   : bc.CKEY,
-        ctype  : bc.types
-    };
+    match verify::verify(&data, &code, &dispatchdata) {
+        Ok(()) => (),
+        Err(errors) => {
+            for err in errors.iter() {
+                println!("verify: {}", err);
+            }
+            os::set_exit_status(1);
+            return;
+        }
+    }
 
-    let dispatchdata  = DispatchData {
-        vtable : bc.vtable
-    };
+    let mut vm = Vm::new(data, code, dispatchdata);
 
-    let code = Code {
-        ip : 0,
-        func : bc.bytecode
-    };
+    match matches.opt_str("gc-threshold") {
+        Some(n) => match from_str(n.as_slice()) {
+            Some(n) => vm.opts.gc_threshold = n,
+            None => {
+                println!("invalid --gc-threshold: {}", n);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
+    }
+    vm.opts.gc_report = matches.opt_present("gc-report");
 
-    Ok((data, code, dispatchdata))
-}
+    match matches.opt_str("max-slots") {
+        Some(n) => match from_str(n.as_slice()) {
+            Some(n) => vm.opts.max_slots = n,
+            None => {
+                println!("invalid --max-slots: {}", n);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
+    }
+    match matches.opt_str("max-depth") {
+        Some(n) => match from_str(n.as_slice()) {
+            Some(n) => vm.opts.max_depth = n,
+            None => {
+                println!("invalid --max-depth: {}", n);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
+    }
 
+    vm.opts.jit = matches.opt_present("jit");
+    match matches.opt_str("jit-threshold") {
+        Some(n) => match from_str(n.as_slice()) {
+            Some(n) => vm.opts.jit_threshold = n,
+            None => {
+                println!("invalid --jit-threshold: {}", n);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
+    }
 
-fn main() {
-    let args = os::args();
-    if args.len() < 2 {
-        println!("usage: {} input.json", args[0]);
-        os::set_exit_status(1);
-        return;
+    vm.opts.trace_jit = matches.opt_present("trace-jit");
+    match matches.opt_str("trace-jit-threshold") {
+        Some(n) => match from_str(n.as_slice()) {
+            Some(n) => vm.opts.trace_jit_threshold = n,
+            None => {
+                println!("invalid --trace-jit-threshold: {}", n);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
     }
 
-    let path = Path::new(args[1].as_slice());
+    if matches.opt_present("debug") {
+        let mut dbg = Debugger::new();
+        for ip in matches.opt_strs("break").iter() {
+            match from_str(ip.as_slice()) {
+                Some(ip) => { dbg.breakpoints.insert(ip); }
+                None => {
+                    println!("invalid --break: {}", ip);
+                    os::set_exit_status(1);
+                    return;
+                }
+            }
+        }
+        dbg.break_on_entry = matches.opt_present("break-entry");
+        vm.debugger = Some(dbg);
+    }
 
-    match parse_json(&path) {
-        Ok((data, code, dispatchdata)) => Vm::new(data, code, dispatchdata).start(),
-        Err(err) => println!("{}", err)
-    };
+    match matches.opt_str("trace") {
+        Some(file) => match make_tracer(&matches, file.as_slice()) {
+            Ok(tracer) => vm.tracer = Some(tracer),
+            Err(err) => {
+                println!("{}", err);
+                os::set_exit_status(1);
+                return;
+            }
+        },
+        None => ()
+    }
+
+    if matches.opt_present("profile") || matches.opt_present("profile-folded") {
+        vm.profiler = Some(Profiler::new());
+    }
+
+    match vm.start() {
+        Ok(()) => (),
+        Err(err) => {
+            println!("error: {}", err.display(&vm.code));
+            os::set_exit_status(1);
+        }
+    }
+
+    match vm.profiler {
+        Some(ref mut profiler) => {
+            if matches.opt_present("profile") {
+                let _ = profiler.report(&mut io::stderr(), &vm.code);
+            }
+            match matches.opt_str("profile-folded") {
+                Some(file) => match profiler.write_folded(&Path::new(file.as_slice()),
+                                                             &vm.code) {
+                    Ok(()) => (),
+                    Err(err) => {
+                        println!("{}: {}", file, err);
+                        os::set_exit_status(1);
+                    }
+                },
+                None => ()
+            }
+        }
+        None => ()
+    }
 }
@@ -0,0 +1,218 @@
+use std::io;
+use std::io::IoResult;
+use std::num::FromPrimitive;
+use std::collections::HashMap;
+
+use vm;
+use vm::Instr;
+use vm::OpCode;
+use vm::InstrPtr;
+use vm::Code;
+
+use decode::from_instr;
+use decode::OpABC;
+
+use time;
+
+struct FuncStats {
+    calls  : u64,
+    // Instructions executed in the function itself, without callees.
+    instrs : u64,
+    // Time spent in the function including its callees.
+    time_ns : u64,
+}
+
+struct Frame {
+    // Entry point of the function, the FUNCF/FUNCV the CALL jumped to.
+    entry : InstrPtr,
+    stack_id : uint,
+    start_ns : u64,
+}
+
+// A call stack is its caller's stack plus the function it entered.
+struct StackNode {
+    parent : Option<uint>,
+    entry  : InstrPtr,
+    count  : u64,
+}
+
+// Counts every executed instruction per opcode, per function and per call
+// stack. Functions are identified by the instruction index CALL jumped to,
+// the code outside of any function is reported as `main`.
+pub struct Profiler {
+    op_counts : Vec<u64>,
+    funcs     : HashMap<InstrPtr, FuncStats>,
+    frames    : Vec<Frame>,
+    // Frames of every function on the stack, recursion is only timed by
+    // the outermost one.
+    active    : HashMap<InstrPtr, uint>,
+    // Call stacks by their (caller stack, function), the ids index `stacks`.
+    stack_ids : HashMap<(Option<uint>, InstrPtr), uint>,
+    stacks    : Vec<StackNode>,
+    start_ns  : u64,
+    // The last counted instruction was a TCALL.
+    tail_call : bool,
+}
+
+static MAIN : InstrPtr = -1;
+
+fn func_name(code: &Code, entry: InstrPtr) -> String {
+    match code.names.find(&entry) {
+        Some(name) => name.clone(),
+        None if entry == MAIN => "main".to_string(),
+        None => format!("fn{}", entry)
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        let mut p = Profiler {
+            op_counts : Vec::from_elem(256, 0u64),
+            funcs     : HashMap::new(),
+            frames    : vec![],
+            active    : HashMap::new(),
+            stack_ids : HashMap::new(),
+            stacks    : vec![],
+            start_ns  : time::precise_time_ns(),
+            tail_call : false,
+        };
+        p.enter(MAIN);
+        p
+    }
+
+    fn stack_id(&mut self, parent: Option<uint>, entry: InstrPtr) -> uint {
+        match self.stack_ids.find(&(parent, entry)) {
+            Some(id) => return *id,
+            None => ()
+        }
+        let id = self.stacks.len();
+        self.stacks.push(StackNode { parent: parent, entry: entry, count: 0 });
+        self.stack_ids.insert((parent, entry), id);
+        id
+    }
+
+    fn enter(&mut self, entry: InstrPtr) {
+        let parent = self.frames.last().map(|caller| caller.stack_id);
+        let stack_id = self.stack_id(parent, entry);
+        *self.active.find_or_insert(entry, 0) += 1;
+
+        self.funcs.find_or_insert_with(entry, |_| {
+            FuncStats { calls: 0, instrs: 0, time_ns: 0 }
+        }).calls += 1;
+
+        self.frames.push(Frame {
+            entry : entry,
+            stack_id : stack_id,
+            start_ns : time::precise_time_ns(),
+        });
+    }
+
+    fn leave(&mut self) {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return
+        };
+
+        // Recursive calls are already covered by the outermost frame.
+        let active = {
+            let active = self.active.get_mut(&frame.entry);
+            *active -= 1;
+            *active
+        };
+        if active > 0 {
+            return;
+        }
+
+        let elapsed = time::precise_time_ns() - frame.start_ns;
+        self.funcs.get_mut(&frame.entry).time_ns += elapsed;
+    }
+
+    // Called by Vm::start before `instr` executes.
+    pub fn count(&mut self, instr: &Instr) {
+        let op = from_instr::<OpABC>(instr).op;
+        *self.op_counts.get_mut(op as uint) += 1;
+        self.tail_call = op == vm::TCALL as u8;
+
+        let frame = self.frames.last().unwrap();
+        self.stacks.get_mut(frame.stack_id).count += 1;
+        self.funcs.get_mut(&frame.entry).instrs += 1;
+    }
+
+    // Called by Vm::start after an instruction executed, with the depth of
+    // Vm.stack before and after it. A deeper stack means a CALL entered the
+    // function at `ip`, a shallower one means a RET left the current one.
+    // A TCALL that kept the depth replaced the current function.
+    pub fn update(&mut self, depth_before: uint, depth_after: uint, ip: InstrPtr) {
+        if self.tail_call && depth_after == depth_before {
+            self.leave();
+            self.enter(ip);
+        } else if depth_after > depth_before {
+            self.enter(ip);
+        } else if depth_after < depth_before {
+            for _ in range(depth_after, depth_before) {
+                self.leave();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        while !self.frames.is_empty() {
+            self.leave();
+        }
+    }
+
+    pub fn report<W: Writer>(&mut self, w: &mut W, code: &Code) -> IoResult<()> {
+        self.finish();
+
+        let total: u64 = self.op_counts.iter().fold(0, |a, &b| a + b);
+        let total_ns = time::precise_time_ns() - self.start_ns;
+
+        try!(writeln!(w, "{} instructions in {} ms", total, total_ns / 1000000));
+
+        try!(writeln!(w, "\nopcode            count      %"));
+        let mut ops: Vec<(u64, uint)> = self.op_counts.iter().enumerate()
+                                            .filter(|&(_, &n)| n > 0)
+                                            .map(|(op, &n)| (n, op)).collect();
+        ops.sort_by(|a, b| b.cmp(a));
+        for &(n, op) in ops.iter() {
+            let op: OpCode = FromPrimitive::from_uint(op).unwrap();
+            try!(writeln!(w, "{:<12} {:>10} {:>6.2}", op.to_string(), n,
+                          100.0 * n as f64 / total as f64));
+        }
+
+        try!(writeln!(w, "\nfunction        calls     instrs    incl ms"));
+        let mut funcs: Vec<(&InstrPtr, &FuncStats)> = self.funcs.iter().collect();
+        funcs.sort_by(|&(_, a), &(_, b)| b.time_ns.cmp(&a.time_ns));
+        for &(entry, stats) in funcs.iter() {
+            try!(writeln!(w, "{:<12} {:>8} {:>10} {:>10.3}", func_name(code, *entry),
+                          stats.calls, stats.instrs, stats.time_ns as f64 / 1e6));
+        }
+
+        Ok(())
+    }
+
+    // Writes the call stacks in the folded format of flamegraph.pl, each
+    // stack weighted by the instructions executed in it. A stack only knows
+    // its caller's stack, the names are joined here.
+    pub fn write_folded(&mut self, path: &Path, code: &Code) -> IoResult<()> {
+        self.finish();
+
+        let mut w = io::BufferedWriter::new(try!(io::File::create(path)));
+        for node in self.stacks.iter().filter(|node| node.count > 0) {
+            let mut names = vec![func_name(code, node.entry)];
+            let mut parent = node.parent;
+            loop {
+                match parent {
+                    Some(id) => {
+                        names.push(func_name(code, self.stacks[id].entry));
+                        parent = self.stacks[id].parent;
+                    }
+                    None => break
+                }
+            }
+            names.reverse();
+            try!(writeln!(w, "{} {}", names.connect(";"), node.count));
+        }
+        w.flush()
+    }
+}
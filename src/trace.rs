@@ -0,0 +1,166 @@
+use std::io;
+use std::collections::HashSet;
+
+use serialize::json;
+
+use vm;
+use vm::Vm;
+use vm::Instr;
+use vm::InstrPtr;
+use vm::BasePtr;
+
+use decode::Decode;
+use decode::from_instr;
+use decode::{OpABC, OpAD};
+
+pub enum Format {
+    Text,
+    JsonLines,
+}
+
+#[deriving(Encodable)]
+struct TraceSlot {
+    slot : uint,
+    val  : String,
+}
+
+#[deriving(Encodable)]
+pub struct TraceEntry {
+    ip    : InstrPtr,
+    base  : BasePtr,
+    op    : String,
+    instr : String,
+    read  : Vec<TraceSlot>,
+    wrote : Vec<TraceSlot>,
+}
+
+pub struct Tracer {
+    pub format : Format,
+    // Only trace these opcodes, all of them if empty.
+    pub ops    : HashSet<u8>,
+    // Only trace instructions in this range, start inclusive, end exclusive.
+    pub range  : Option<(InstrPtr, InstrPtr)>,
+    out : Box<Writer + 'static>,
+}
+
+fn range_incl(from: uint, to: uint) -> Vec<uint> {
+    range(from, to + 1).collect()
+}
+
+// The slots an instruction reads and writes, relative to the frame base it
+// executes in. Writes done by the callee after a CALL are not included.
+pub fn effects(instr: &Instr) -> (Vec<uint>, Vec<uint>) {
+    let abc = from_instr::<OpABC>(instr);
+    let ad = from_instr::<OpAD>(instr);
+    let (a, b, c, d) = (abc.a as uint, abc.b as uint, abc.c as uint, ad.d as uint);
+
+    match instr.decode() {
+        Ok(vm::CSTR) | Ok(vm::CKEY) | Ok(vm::CINT) | Ok(vm::CSHORT) |
+        Ok(vm::CFLOAT) | Ok(vm::CBOOL) | Ok(vm::CNIL) | Ok(vm::CTYPE) |
+        Ok(vm::NSGETS) | Ok(vm::VFNEW) => (vec![], vec![a]),
+        Ok(vm::MFNEW) => (vec![1], vec![a]),
+        Ok(vm::NSSETS) | Ok(vm::JUMPF) | Ok(vm::JUMPT) | Ok(vm::RET) |
+        Ok(vm::THROW) => (vec![a], vec![]),
+        Ok(vm::ADDVV) | Ok(vm::SUBVV) | Ok(vm::MULVV) | Ok(vm::DIVVV) |
+        Ok(vm::MODVV) | Ok(vm::POWVV) | Ok(vm::ISLT) | Ok(vm::ISGE) |
+        Ok(vm::ISLE) | Ok(vm::ISGT) | Ok(vm::ISEQ) | Ok(vm::ISNEQ) |
+        Ok(vm::GETARRAY) => (vec![b, c], vec![a]),
+        Ok(vm::MOV) | Ok(vm::NOT) | Ok(vm::NEG) | Ok(vm::ALLOC) => (vec![d], vec![a]),
+        Ok(vm::FNEW) | Ok(vm::GETFREEVAR) => (vec![1], vec![a]),
+        Ok(vm::CALL) => (range_incl(a + 1, a + 1 + d), vec![a]),
+        Ok(vm::TCALL) => (range_incl(a + 1, a + 1 + d), range_incl(0, 1 + d)),
+        Ok(vm::APPLY) => {
+            let mut read = range_incl(a + 1, a + 1 + c);
+            read.push(b);
+            (read, vec![a])
+        },
+        Ok(vm::DROP) => (vec![], range_incl(a, d)),
+        Ok(vm::UCLO) => (range_incl(a, d), vec![d + 1]),
+        Ok(vm::BULKMOV) => (range(b, b + c).collect(), range(a, a + c).collect()),
+        Ok(vm::NEWARRAY) => (range(b, b + c).collect(), vec![a]),
+        Ok(vm::SETARRAY) => (vec![a, b, c], vec![]),
+        Ok(vm::SETFIELD) | Ok(vm::INITFIELD) => (vec![a, c], vec![]),
+        Ok(vm::GETFIELD) => (vec![b], vec![a]),
+        _ => (vec![], vec![])
+    }
+}
+
+// Slots past the end of the slot vector are left out, an operand can name
+// a slot the instruction has not reserved yet.
+fn snapshot(vm: &Vm, base: BasePtr, slots: &[uint]) -> Vec<TraceSlot> {
+    slots.iter().filter_map(|&i| {
+        vm.slots.slot.as_slice().get(base + i).map(|val| {
+            TraceSlot { slot: i, val: val.to_string() }
+        })
+    }).collect()
+}
+
+impl Tracer {
+    pub fn new(out: Box<Writer + 'static>, format: Format) -> Tracer {
+        Tracer {
+            format : format,
+            ops    : HashSet::new(),
+            range  : None,
+            out    : out,
+        }
+    }
+
+    pub fn stderr(format: Format) -> Tracer {
+        Tracer::new(box io::stderr() as Box<Writer + 'static>, format)
+    }
+
+    fn wants(&self, ip: InstrPtr, instr: &Instr) -> bool {
+        let in_range = match self.range {
+            Some((from, to)) => from <= ip && ip < to,
+            None => true
+        };
+        in_range && (self.ops.is_empty() ||
+                     self.ops.contains(&from_instr::<OpABC>(instr).op))
+    }
+
+    // Called by Vm::start before `instr` executes, records what it reads.
+    pub fn before(&self, vm: &Vm, instr: &Instr) -> Option<TraceEntry> {
+        if !self.wants(vm.code.ip, instr) {
+            return None;
+        }
+
+        let (read, _) = effects(instr);
+
+        Some(TraceEntry {
+            ip    : vm.code.ip,
+            base  : vm.slots.base,
+            op    : match instr.decode() {
+                        Ok(op) => op.to_string(),
+                        Err(opcode) => opcode.to_string()
+                    },
+            instr : instr.to_string(),
+            read  : snapshot(vm, vm.slots.base, read.as_slice()),
+            wrote : vec![],
+        })
+    }
+
+    // Called after the instruction executed, with the entry from `before`.
+    pub fn after(&mut self, vm: &Vm, instr: &Instr, mut entry: TraceEntry) {
+        let (_, wrote) = effects(instr);
+        entry.wrote = snapshot(vm, entry.base, wrote.as_slice());
+
+        let res = match self.format {
+            JsonLines => writeln!(self.out, "{}", json::encode(&entry)),
+            Text => {
+                let show = |slots: &Vec<TraceSlot>| {
+                    let vals: Vec<String> = slots.iter()
+                        .map(|s| format!("[{}]={}", s.slot, s.val)).collect();
+                    vals.connect(" ")
+                };
+                writeln!(self.out, "{:>6} base {:<5} {:<28} read {} wrote {}",
+                         entry.ip, entry.base, entry.instr,
+                         show(&entry.read), show(&entry.wrote))
+            }
+        };
+
+        match res {
+            Ok(()) => (),
+            Err(err) => error!("failed to write trace: {}", err)
+        }
+    }
+}
@@ -0,0 +1,604 @@
+// Trace recording JIT anchored at LOOP.
+//
+// A LOOP that is hit often enough starts a recording. While recording, every
+// instruction the interpreter executes in the frame of the loop is turned
+// into SSA instructions specialized to the slot types observed at that
+// moment, until execution is back at the LOOP. The next time the LOOP is
+// reached the trace runs instead of the interpreter, keeping the values that
+// are carried from one iteration to the next unboxed, until a guard fails
+// and the trace exits back to the interpreter.
+
+use std::cmp;
+use std::mem;
+use std::collections::{HashMap, HashSet};
+
+use vm;
+use vm::Vm;
+use vm::Slot;
+use vm::{Nil, Int, Float, Bool};
+use vm::{InstrPtr, BasePtr};
+use vm::OpCode;
+
+use decode::Op;
+use fetch::Fetch;
+
+// Longer recordings are aborted.
+static MAX_TRACE_LEN : uint = 1000;
+
+// A loop that failed to record this often is not tried again.
+static MAX_ABORTS : uint = 3;
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum Ty {
+    TInt,
+    TFloat,
+    TBool,
+    TNil,
+}
+
+#[deriving(Clone, Show)]
+pub enum Cmp {
+    Lt, Ge, Le, Gt, Eq, Ne,
+}
+
+pub type Ref = uint;
+
+#[deriving(Clone, Show)]
+pub enum Ins {
+    KInt(i64),
+    KFloat(f64),
+    KBool(bool),
+    KNil,
+    // The value of a frame slot at the start of the iteration. Its type is
+    // checked once when the trace is entered.
+    SLoad(u16, Ty),
+    AddI(Ref, Ref), SubI(Ref, Ref), MulI(Ref, Ref),
+    AddF(Ref, Ref), SubF(Ref, Ref), MulF(Ref, Ref), DivF(Ref, Ref),
+    NegI(Ref), NegF(Ref),
+    NotB(Ref),
+    ConvIF(Ref),
+    CmpI(Cmp, Ref, Ref),
+    CmpF(Cmp, Ref, Ref),
+    // Leaves the trace through the snapshot unless the Bool is as expected.
+    Guard(Ref, bool, uint),
+    Nop,
+}
+
+// Where to continue in the interpreter and the slots written so far in the
+// current iteration.
+pub struct Snapshot {
+    pub ip    : InstrPtr,
+    pub slots : Vec<(u16, Ref)>,
+}
+
+pub struct Trace {
+    pub anchor    : InstrPtr,
+    pub ins       : Vec<Ins>,
+    pub types     : Vec<Ty>,
+    pub snapshots : Vec<Snapshot>,
+    // The slots one iteration writes and their values at its end.
+    pub tail      : Vec<(u16, Ref)>,
+}
+
+pub struct Recorder {
+    anchor    : InstrPtr,
+    base      : BasePtr,
+    ins       : Vec<Ins>,
+    types     : Vec<Ty>,
+    snapshots : Vec<Snapshot>,
+    slots     : HashMap<u16, Ref>,
+    written   : Vec<u16>,
+}
+
+pub struct TraceJit {
+    counters : HashMap<InstrPtr, uint>,
+    aborts   : HashMap<InstrPtr, uint>,
+    traces   : HashMap<InstrPtr, Trace>,
+    pub recording : Option<Recorder>,
+}
+
+enum Step {
+    Continue,
+    Done,
+    Abort(&'static str),
+}
+
+impl TraceJit {
+    pub fn new() -> TraceJit {
+        TraceJit {
+            counters : HashMap::new(),
+            aborts   : HashMap::new(),
+            traces   : HashMap::new(),
+            recording : None,
+        }
+    }
+}
+
+fn slot_type(slot: &Slot) -> Option<Ty> {
+    match *slot {
+        Int(_) => Some(TInt),
+        Float(_) => Some(TFloat),
+        Bool(_) => Some(TBool),
+        Nil => Some(TNil),
+        _ => None
+    }
+}
+
+fn f2b(f: f64) -> u64 {
+    unsafe { mem::transmute(f) }
+}
+
+fn b2f(b: u64) -> f64 {
+    unsafe { mem::transmute(b) }
+}
+
+fn unbox(slot: &Slot, ty: Ty) -> Option<u64> {
+    match (slot, ty) {
+        (&Int(i), TInt) => Some(i as u64),
+        (&Float(f), TFloat) => Some(f2b(f)),
+        (&Bool(b), TBool) => Some(b as u64),
+        (&Nil, TNil) => Some(0),
+        _ => None
+    }
+}
+
+fn box_val(val: u64, ty: Ty) -> Slot {
+    match ty {
+        TInt => Int(val as i64),
+        TFloat => Float(b2f(val)),
+        TBool => Bool(val != 0),
+        TNil => Nil
+    }
+}
+
+fn compare<T: PartialOrd>(cmp: Cmp, x: T, y: T) -> bool {
+    match cmp {
+        Lt => x < y, Ge => x >= y, Le => x <= y,
+        Gt => x > y, Eq => x == y, Ne => x != y,
+    }
+}
+
+fn operands(ins: &Ins) -> Vec<Ref> {
+    match *ins {
+        AddI(x, y) | SubI(x, y) | MulI(x, y) |
+        AddF(x, y) | SubF(x, y) | MulF(x, y) | DivF(x, y) |
+        CmpI(_, x, y) | CmpF(_, x, y) => vec![x, y],
+        NegI(x) | NegF(x) | NotB(x) | ConvIF(x) | Guard(x, _, _) => vec![x],
+        _ => vec![]
+    }
+}
+
+impl Recorder {
+    fn new(anchor: InstrPtr, base: BasePtr) -> Recorder {
+        Recorder {
+            anchor    : anchor,
+            base      : base,
+            ins       : vec![],
+            types     : vec![],
+            snapshots : vec![],
+            slots     : HashMap::new(),
+            written   : vec![],
+        }
+    }
+
+    // Constant folding, instructions on constants are replaced by their
+    // result.
+    fn fold(&self, ins: &Ins) -> Option<Ins> {
+        let k = |r: Ref| self.ins[r].clone();
+        match *ins {
+            AddI(x, y) => match (k(x), k(y)) { (KInt(a), KInt(b)) => Some(KInt(a + b)), _ => None },
+            SubI(x, y) => match (k(x), k(y)) { (KInt(a), KInt(b)) => Some(KInt(a - b)), _ => None },
+            MulI(x, y) => match (k(x), k(y)) { (KInt(a), KInt(b)) => Some(KInt(a * b)), _ => None },
+            AddF(x, y) => match (k(x), k(y)) { (KFloat(a), KFloat(b)) => Some(KFloat(a + b)), _ => None },
+            SubF(x, y) => match (k(x), k(y)) { (KFloat(a), KFloat(b)) => Some(KFloat(a - b)), _ => None },
+            MulF(x, y) => match (k(x), k(y)) { (KFloat(a), KFloat(b)) => Some(KFloat(a * b)), _ => None },
+            DivF(x, y) => match (k(x), k(y)) { (KFloat(a), KFloat(b)) => Some(KFloat(a / b)), _ => None },
+            NegI(x) => match k(x) { KInt(a) => Some(KInt(-a)), _ => None },
+            NegF(x) => match k(x) { KFloat(a) => Some(KFloat(-a)), _ => None },
+            NotB(x) => match k(x) { KBool(a) => Some(KBool(!a)), _ => None },
+            ConvIF(x) => match k(x) { KInt(a) => Some(KFloat(a as f64)), _ => None },
+            CmpI(cmp, x, y) => match (k(x), k(y)) {
+                (KInt(a), KInt(b)) => Some(KBool(compare(cmp, a, b))), _ => None
+            },
+            CmpF(cmp, x, y) => match (k(x), k(y)) {
+                (KFloat(a), KFloat(b)) => Some(KBool(compare(cmp, a, b))), _ => None
+            },
+            // A guard that always holds is dropped.
+            Guard(x, expect, _) => match k(x) { KBool(a) if a == expect => Some(Nop), _ => None },
+            _ => None
+        }
+    }
+
+    fn emit(&mut self, ins: Ins, ty: Ty) -> Ref {
+        let ins = self.fold(&ins).unwrap_or(ins);
+        self.ins.push(ins);
+        self.types.push(ty);
+        self.ins.len() - 1
+    }
+
+    fn snapshot(&mut self, ip: InstrPtr) -> uint {
+        let slots = self.written.iter().map(|s| (*s, self.slots[*s])).collect();
+        self.snapshots.push(Snapshot { ip: ip, slots: slots });
+        self.snapshots.len() - 1
+    }
+
+    fn get(&mut self, vm: &Vm, slot: u16) -> Option<Ref> {
+        match self.slots.find(&slot) {
+            Some(r) => return Some(*r),
+            None => ()
+        }
+
+        if vm.slots.base + slot as uint >= vm.slots.slot.len() {
+            return None;
+        }
+
+        let ty = match slot_type(&vm.slots[slot]) {
+            Some(ty) => ty,
+            None => return None
+        };
+
+        let r = self.emit(SLoad(slot, ty.clone()), ty);
+        self.slots.insert(slot, r);
+        Some(r)
+    }
+
+    fn set(&mut self, slot: u16, r: Ref) {
+        if !self.written.contains(&slot) {
+            self.written.push(slot);
+        }
+        self.slots.insert(slot, r);
+    }
+
+    fn to_float(&mut self, r: Ref) -> Ref {
+        match self.types[r] {
+            TInt => self.emit(ConvIF(r), TFloat),
+            _ => r
+        }
+    }
+
+    fn arith(&mut self, vm: &Vm, op: OpCode, a: u16, b: u16, c: u16) -> Step {
+        let (x, y) = match (self.get(vm, b), self.get(vm, c)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Abort("unsupported operand type")
+        };
+
+        let r = match (self.types[x].clone(), self.types[y].clone(), op) {
+            (TInt, TInt, vm::ADDVV) => self.emit(AddI(x, y), TInt),
+            (TInt, TInt, vm::SUBVV) => self.emit(SubI(x, y), TInt),
+            (TInt, TInt, vm::MULVV) => self.emit(MulI(x, y), TInt),
+            (TInt, _, _) | (TFloat, _, _) => match self.types[y] {
+                TInt | TFloat => {
+                    let (x, y) = (self.to_float(x), self.to_float(y));
+                    let ins = match op {
+                        vm::ADDVV => AddF(x, y),
+                        vm::SUBVV => SubF(x, y),
+                        vm::MULVV => MulF(x, y),
+                        _ => DivF(x, y)
+                    };
+                    self.emit(ins, TFloat)
+                },
+                _ => return Abort("unsupported operand type")
+            },
+            _ => return Abort("unsupported operand type")
+        };
+
+        self.set(a, r);
+        Continue
+    }
+
+    fn compare(&mut self, vm: &Vm, op: OpCode, a: u16, b: u16, c: u16) -> Step {
+        let (x, y) = match (self.get(vm, b), self.get(vm, c)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Abort("unsupported operand type")
+        };
+
+        let cmp = match op {
+            vm::ISLT => Lt, vm::ISGE => Ge, vm::ISLE => Le,
+            vm::ISGT => Gt, vm::ISEQ => Eq, _ => Ne
+        };
+
+        let r = match (self.types[x].clone(), self.types[y].clone()) {
+            (TInt, TInt) => self.emit(CmpI(cmp, x, y), TBool),
+            (TInt, TFloat) | (TFloat, TInt) | (TFloat, TFloat) => {
+                let (x, y) = (self.to_float(x), self.to_float(y));
+                self.emit(CmpF(cmp, x, y), TBool)
+            },
+            _ => return Abort("unsupported operand type")
+        };
+
+        self.set(a, r);
+        Continue
+    }
+
+    // Records `op` before the interpreter executes it.
+    fn step(&mut self, vm: &Vm, op: &Op) -> Step {
+        let ip = vm.code.ip;
+
+        if vm.slots.base != self.base {
+            return Abort("left the frame of the loop");
+        }
+        if self.ins.len() > MAX_TRACE_LEN {
+            return Abort("trace too long");
+        }
+
+        // D operands reach past the 8 bit slots, so slots are u16 throughout.
+        let (a, b, c, d) = (op.a as u16, op.b as u16, op.c as u16, op.d);
+
+        match op.op {
+            vm::LOOP if ip == self.anchor => Done,
+            vm::LOOP => Abort("inner loop"),
+
+            vm::CSHORT => { let r = self.emit(KInt(d as i16 as i64), TInt); self.set(a, r); Continue },
+            vm::CINT => { let r = self.emit(KInt(vm.data.cint[d as uint]), TInt); self.set(a, r); Continue },
+            vm::CFLOAT => { let r = self.emit(KFloat(vm.data.cfloat[d as uint]), TFloat); self.set(a, r); Continue },
+            vm::CBOOL => { let r = self.emit(KBool(d == 1), TBool); self.set(a, r); Continue },
+            vm::CNIL => { let r = self.emit(KNil, TNil); self.set(a, r); Continue },
+
+            vm::MOV => match self.get(vm, d) {
+                Some(r) => { self.set(a, r); Continue },
+                None => Abort("unsupported operand type")
+            },
+
+            vm::ADDVV | vm::SUBVV | vm::MULVV | vm::DIVVV => self.arith(vm, op.op, a, b, c),
+
+            vm::ISLT | vm::ISGE | vm::ISLE | vm::ISGT | vm::ISEQ | vm::ISNEQ =>
+                self.compare(vm, op.op, a, b, c),
+
+            vm::NEG => match self.get(vm, d) {
+                Some(x) => {
+                    let r = match self.types[x] {
+                        TInt => self.emit(NegI(x), TInt),
+                        TFloat => self.emit(NegF(x), TFloat),
+                        _ => return Abort("unsupported operand type")
+                    };
+                    self.set(a, r);
+                    Continue
+                },
+                None => Abort("unsupported operand type")
+            },
+
+            vm::NOT => match self.get(vm, d) {
+                Some(x) => {
+                    let r = match self.types[x] {
+                        TBool => self.emit(NotB(x), TBool),
+                        TNil => self.emit(KBool(true), TBool),
+                        _ => self.emit(KBool(false), TBool)
+                    };
+                    self.set(a, r);
+                    Continue
+                },
+                None => Abort("unsupported operand type")
+            },
+
+            vm::JUMP => Continue,
+
+            // The recording follows the branch the interpreter takes, the
+            // guard exits to the other one. Only Bools need a guard, the
+            // truthiness of the other types is fixed by their type.
+            vm::JUMPF | vm::JUMPT => match self.get(vm, a) {
+                Some(x) => {
+                    let truthy = match vm.slots[a] {
+                        Nil | Bool(false) => false,
+                        _ => true
+                    };
+                    let taken = truthy == (op.op == vm::JUMPT);
+                    let other = if taken { ip + 1 } else { (ip as int + d as i16 as int) as uint };
+                    if self.types[x] == TBool {
+                        let snap = self.snapshot(other);
+                        self.emit(Guard(x, truthy, snap), TNil);
+                    }
+                    Continue
+                },
+                None => Abort("unsupported operand type")
+            },
+
+            _ => Abort("unsupported instruction")
+        }
+    }
+
+    fn finish(self) -> Result<Trace, &'static str> {
+        let tail: Vec<(u16, Ref)> = self.written.iter().map(|s| (*s, self.slots[*s])).collect();
+
+        // Loop carried values stay unboxed, so a slot has to keep the type
+        // it was loaded with.
+        for &(slot, r) in tail.iter() {
+            for (i, ins) in self.ins.iter().enumerate() {
+                match *ins {
+                    SLoad(s, _) if s == slot && self.types[i] != self.types[r] =>
+                        return Err("slot changes its type in the loop"),
+                    _ => ()
+                }
+            }
+        }
+
+        // Dead code elimination, everything not reachable from a guard,
+        // a snapshot or the tail is dropped.
+        let mut live = HashSet::new();
+        let mut work: Vec<Ref> = tail.iter().map(|&(_, r)| r).collect();
+        for (i, ins) in self.ins.iter().enumerate() {
+            match *ins {
+                Guard(..) => work.push(i),
+                SLoad(..) => work.push(i),
+                _ => ()
+            }
+        }
+        for snap in self.snapshots.iter() {
+            work.extend(snap.slots.iter().map(|&(_, r)| r));
+        }
+        loop {
+            let r = match work.pop() { Some(r) => r, None => break };
+            if live.insert(r) {
+                work.push_all(operands(&self.ins[r]).as_slice());
+            }
+        }
+
+        let ins = self.ins.iter().enumerate().map(|(i, ins)| {
+            if live.contains(&i) { ins.clone() } else { Nop }
+        }).collect();
+
+        Ok(Trace {
+            anchor    : self.anchor,
+            ins       : ins,
+            types     : self.types,
+            snapshots : self.snapshots,
+            tail      : tail,
+        })
+    }
+}
+
+// Called before the interpreter executes `op` while a recording is active.
+pub fn record(vm: &mut Vm, op: &Op) {
+    let mut rec = vm.tracejit.recording.take().unwrap();
+    let anchor = rec.anchor;
+
+    let res = match rec.step(vm, op) {
+        Continue => { vm.tracejit.recording = Some(rec); return },
+        Done => rec.finish(),
+        Abort(reason) => Err(reason)
+    };
+
+    match res {
+        Ok(trace) => {
+            info!("tracejit: recorded loop at {}, {} instructions", trace.anchor, trace.ins.len());
+            vm.tracejit.traces.insert(trace.anchor, trace);
+        }
+        Err(reason) => {
+            info!("tracejit: aborted recording of loop at {}: {}", anchor, reason);
+            *vm.tracejit.aborts.find_or_insert(anchor, 0) += 1;
+        }
+    }
+}
+
+// Runs the trace until a guard fails, returns where the interpreter has to
+// continue.
+fn run_trace(vm: &mut Vm, trace: &Trace) -> InstrPtr {
+    let base = vm.slots.base;
+    let n = trace.ins.len();
+    let mut vals = Vec::from_elem(n, 0u64);
+
+    // Entry, check and unbox the slots the loop reads.
+    let mut sloads = HashMap::new();
+    for (i, ins) in trace.ins.iter().enumerate() {
+        match *ins {
+            SLoad(slot, ref ty) => {
+                match unbox(&vm.slots.slot[base + slot as uint], ty.clone()) {
+                    Some(v) => *vals.get_mut(i) = v,
+                    None => return trace.anchor + 1
+                }
+                sloads.insert(slot, i);
+            }
+            _ => ()
+        }
+    }
+
+    // Values of the tail slots at the start of the current iteration, None
+    // while the frame still holds them.
+    let mut carried: Option<Vec<u64>> = None;
+
+    loop {
+        for i in range(0, n) {
+            let val = {
+                let v = |r: Ref| vals[r];
+                match trace.ins[i] {
+                    KInt(k) => k as u64,
+                    KFloat(f) => f2b(f),
+                    KBool(b) => b as u64,
+                    KNil | Nop => 0,
+                    SLoad(..) => continue,
+                    AddI(x, y) => (v(x) as i64 + v(y) as i64) as u64,
+                    SubI(x, y) => (v(x) as i64 - v(y) as i64) as u64,
+                    MulI(x, y) => (v(x) as i64 * v(y) as i64) as u64,
+                    AddF(x, y) => f2b(b2f(v(x)) + b2f(v(y))),
+                    SubF(x, y) => f2b(b2f(v(x)) - b2f(v(y))),
+                    MulF(x, y) => f2b(b2f(v(x)) * b2f(v(y))),
+                    DivF(x, y) => f2b(b2f(v(x)) / b2f(v(y))),
+                    NegI(x) => (-(v(x) as i64)) as u64,
+                    NegF(x) => f2b(-b2f(v(x))),
+                    NotB(x) => (v(x) == 0) as u64,
+                    ConvIF(x) => f2b(v(x) as i64 as f64),
+                    CmpI(cmp, x, y) => compare(cmp, v(x) as i64, v(y) as i64) as u64,
+                    CmpF(cmp, x, y) => compare(cmp, b2f(v(x)), b2f(v(y))) as u64,
+                    Guard(x, expect, snap) => {
+                        if (v(x) != 0) != expect {
+                            return exit(vm, trace, &vals, &carried, snap);
+                        }
+                        0
+                    }
+                }
+            };
+            *vals.get_mut(i) = val;
+        }
+
+        // Next iteration, the tail values become the loaded values.
+        let next: Vec<u64> = trace.tail.iter().map(|&(_, r)| vals[r]).collect();
+        for (&(slot, _), v) in trace.tail.iter().zip(next.iter()) {
+            match sloads.find(&slot) {
+                Some(&i) => *vals.get_mut(i) = *v,
+                None => ()
+            }
+        }
+        carried = Some(next);
+    }
+}
+
+fn exit(vm: &mut Vm, trace: &Trace, vals: &Vec<u64>, carried: &Option<Vec<u64>>,
+        snap: uint) -> InstrPtr {
+    let base = vm.slots.base;
+
+    match *carried {
+        Some(ref carried) => {
+            for (&(slot, r), v) in trace.tail.iter().zip(carried.iter()) {
+                *vm.slots.slot.get_mut(base + slot as uint) = box_val(*v, trace.types[r].clone());
+            }
+        }
+        None => ()
+    }
+
+    let snap = &trace.snapshots[snap];
+    for &(slot, r) in snap.slots.iter() {
+        *vm.slots.slot.get_mut(base + slot as uint) = box_val(vals[r], trace.types[r].clone());
+    }
+
+    let written = trace.tail.iter().chain(snap.slots.iter()).map(|&(slot, _)| base + slot as uint + 1);
+    vm.slots.top = cmp::max(vm.slots.top, written.max().unwrap_or(0));
+
+    snap.ip
+}
+
+// Called by LOOP. Runs the trace of the loop if there is one, or starts a
+// recording once the loop is hot. None leaves the LOOP to the baseline JIT.
+pub fn loop_hit(vm: &mut Vm) -> Option<Op> {
+    if !vm.opts.trace_jit || vm.tracejit.recording.is_some() {
+        return None;
+    }
+
+    let ip = vm.code.ip;
+
+    match vm.tracejit.traces.pop(&ip) {
+        Some(trace) => {
+            let exit = run_trace(vm, &trace);
+            vm.tracejit.traces.insert(ip, trace);
+            vm.code.ip = exit;
+            return Some(vm.fetch(0));
+        }
+        None => ()
+    }
+
+    if vm.tracejit.aborts.find(&ip).map_or(false, |&n| n >= MAX_ABORTS) {
+        return None;
+    }
+
+    let hits = {
+        let hits = vm.tracejit.counters.find_or_insert(ip, 0);
+        *hits += 1;
+        *hits
+    };
+
+    if hits >= vm.opts.trace_jit_threshold {
+        *vm.tracejit.counters.get_mut(&ip) = 0;
+        vm.tracejit.recording = Some(Recorder::new(ip, vm.slots.base));
+        return Some(vm.fetch_next());
+    }
+
+    None
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+use vm;
+use vm::Instr;
+use vm::Data;
+use vm::Code;
+use vm::DispatchData;
+use vm::InstrPtr;
+
+use decode::Decode;
+use decode::from_instr;
+use decode::{OpABC, OpAD};
+
+// `instr` is None for errors in the vtable, which point into the code but
+// do not belong to an instruction.
+pub struct VerifyError {
+    pub ip    : InstrPtr,
+    pub instr : Option<Instr>,
+    pub msg   : String,
+}
+
+impl fmt::Show for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instr {
+            Some(ref instr) => write!(f, "{}: {}: {}", self.ip, instr, self.msg),
+            None => write!(f, "{}: {}", self.ip, self.msg)
+        }
+    }
+}
+
+fn check_pool(idx: uint, len: uint, pool: &str) -> Option<String> {
+    if idx < len {
+        None
+    } else {
+        Some(format!("{} constant {} out of range ({} constants)", pool, idx, len))
+    }
+}
+
+fn check_field(offset: uint, max_fields: uint) -> Option<String> {
+    if offset < max_fields {
+        None
+    } else {
+        Some(format!("field offset {} out of range (types have at most {} fields)",
+                     offset, max_fields))
+    }
+}
+
+fn is_func_entry(code: &Code, ip: InstrPtr) -> bool {
+    ip < code.func.len() && match code.func[ip].decode() {
+        Ok(vm::FUNCF) | Ok(vm::FUNCV) => true,
+        _ => false
+    }
+}
+
+// A handler runs in the frame of the instruction that threw, so the range
+// and its target have to belong to the same function.
+fn in_one_function(code: &Code, start: InstrPtr, end: InstrPtr, target: InstrPtr) -> bool {
+    let entry = code.function_entry(target);
+    start == end || (code.function_entry(start) == entry &&
+                     code.function_entry(end - 1) == entry)
+}
+
+// Checks every operand that indexes into the constant pools, the type table,
+// the vtable or the code itself, so the interpreter can trust them. All
+// invalid operands are reported, not just the first one.
+pub fn verify(data: &Data, code: &Code, dd: &DispatchData) -> Result<(), Vec<VerifyError>> {
+    let mut errors = vec![];
+    let len = code.func.len();
+
+    // The type of an object is only known at runtime, an offset past the
+    // largest type can never be valid though.
+    let max_fields = data.ctype.iter().map(|t| t.size).max().unwrap_or(0);
+
+    for (ip, instr) in code.func.iter().enumerate() {
+        let abc = from_instr::<OpABC>(instr);
+        let ad = from_instr::<OpAD>(instr);
+
+        let msg = match instr.decode() {
+            Err(opcode) => Some(format!("invalid opcode {}", opcode)),
+
+            Ok(vm::CSTR) | Ok(vm::NSGETS) => check_pool(ad.d as uint, data.cstr.len(), "CSTR"),
+            Ok(vm::NSSETS) => check_pool(abc.b as uint, data.cstr.len(), "CSTR"),
+            Ok(vm::CKEY)   => check_pool(ad.d as uint, data.ckey.len(), "CKEY"),
+            Ok(vm::CINT)   => check_pool(ad.d as uint, data.cint.len(), "CINT"),
+            Ok(vm::CFLOAT) => check_pool(ad.d as uint, data.cfloat.len(), "CFLOAT"),
+
+            Ok(vm::CTYPE) if ad.d as uint >= data.ctype.len() =>
+                Some(format!("type {} out of range ({} types)", ad.d, data.ctype.len())),
+
+            Ok(vm::JUMP) | Ok(vm::JUMPF) | Ok(vm::JUMPT) => {
+                let target = ip as int + ad.d as i16 as int;
+                if target < 0 || target >= len as int {
+                    Some(format!("jump target {} out of range ({} instructions)",
+                                 target, len))
+                } else {
+                    None
+                }
+            },
+
+            Ok(vm::FNEW) if !is_func_entry(code, ad.d as uint) =>
+                Some(format!("function {} is not a FUNCF or FUNCV", ad.d)),
+
+            Ok(vm::VFNEW) if !dd.vtable.contains_key(&(ad.d as uint)) =>
+                Some(format!("no vtable entry for {}", ad.d)),
+
+            Ok(vm::MFNEW) if !dd.arities.contains_key(&(ad.d as uint)) =>
+                Some(format!("no arity table entry for {}", ad.d)),
+
+            Ok(vm::SETFIELD) | Ok(vm::INITFIELD) => check_field(abc.b as uint, max_fields),
+            Ok(vm::GETFIELD) => check_field(abc.c as uint, max_fields),
+
+            _ => None
+        };
+
+        match msg {
+            Some(msg) => errors.push(VerifyError { ip: ip, instr: Some(*instr), msg: msg }),
+            None => ()
+        }
+    }
+
+    for (vfunc, impls) in dd.vtable.iter() {
+        for (ty, func) in impls.iter() {
+            if !is_func_entry(code, *func) {
+                errors.push(VerifyError {
+                    ip: *func,
+                    instr: None,
+                    msg: format!("vtable entry {} for type {} is not a FUNCF or FUNCV",
+                                 vfunc, ty)
+                });
+            }
+        }
+    }
+
+    for (table, arities) in dd.arities.iter() {
+        for func in arities.entries().iter() {
+            if !is_func_entry(code, *func) {
+                errors.push(VerifyError {
+                    ip: *func,
+                    instr: None,
+                    msg: format!("arity table entry {} is not a FUNCF or FUNCV", table)
+                });
+            }
+        }
+    }
+
+    for h in code.handlers.iter() {
+        let msg = if h.start > h.end || h.end > len {
+            Some(format!("handler range {}..{} out of range ({} instructions)",
+                         h.start, h.end, len))
+        } else if h.target >= len {
+            Some(format!("handler target {} out of range ({} instructions)", h.target, len))
+        } else if !in_one_function(code, h.start, h.end, h.target) {
+            Some(format!("handler target {} is not in the function of {}..{}",
+                         h.target, h.start, h.end))
+        } else if h.catch_type.map_or(false, |nr| data.find_type(nr).is_none()) {
+            Some(format!("handler catches unknown type {}", h.catch_type.unwrap()))
+        } else if h.slot > 255 {
+            Some(format!("handler slot {} out of range", h.slot))
+        } else {
+            None
+        };
+
+        match msg {
+            Some(msg) => errors.push(VerifyError { ip: h.start, instr: None, msg: msg }),
+            None => ()
+        }
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
@@ -1,12 +1,34 @@
+use std::io;
+use std::cmp;
 use std::default::Default;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 use fetch::Fetch;
+use decode;
 use decode::Decode;
+use decode::Op;
 use execute::Execute;
 use builtin::println;
+use error::{VmError, VmResult, ErrorKind, InvalidOpcode, StackOverflow, InvalidBytecode};
+use error::StackFrame;
+use gc;
+use exception;
+use verify;
+use gc::Heap;
+use debug;
+use debug::Debugger;
+use trace::Tracer;
+use profile::Profiler;
+use jit::Jit;
+use tracejit;
+use tracejit::TraceJit;
+
+use time;
 
 use std::fmt;
+use std::num::FromPrimitive;
 
 #[deriving(Clone)]
 pub struct Instr(pub u32);
@@ -28,7 +50,12 @@ pub enum OpCode {
     NEWARRAY, GETARRAY, SETARRAY,
     ALLOC, SETFIELD, GETFIELD,
     FUNCF, FUNCV,
-    EXIT
+    EXIT,
+    // Added later, appended to keep the numbers of the ones above.
+    INITFIELD,
+    MFNEW,
+    TCALL,
+    THROW
 }
 
 pub enum InstrType {
@@ -48,10 +75,12 @@ pub enum Slot {
     Key(Keyword),
     Func(uint),
     VFunc(uint),
-    Obj(CljObject),
+    MFunc(uint),
+    Obj(CljObjectRef),
     CType(uint),
     SCC(Closure),
     Builtin(fn (&mut Vm)),
+    Array(CljArray),
 }
 
 #[deriving(Clone)]
@@ -66,14 +95,24 @@ type CFloat = Vec<f64>;
 type CStr   = Vec<String>;
 type CKey   = Vec<Keyword>;
 type VTable = HashMap<uint,HashMap<uint,uint>>;
+type ArityTable = HashMap<uint,Arities>;
 type Types  = Vec<CljType>;
 type Fields = Vec<CljField>;
 type RawSlots = Vec<Slot>;
 
+// Arrays are shared by reference, a SETARRAY is visible through every
+// slot holding the same array.
+pub type CljArray = Rc<RefCell<Vec<Slot>>>;
+
+// Objects are shared the same way, every slot, field or free var holding
+// the reference sees SETFIELD writes.
+pub type CljObjectRef = Rc<RefCell<CljObject>>;
+
 pub type InstrPtr = uint;
 pub type FuncPtr = uint;
 pub type BasePtr = uint;
 
+#[deriving(Clone)]
 pub struct Data {
     pub cint   : CInt,
     pub cfloat : CFloat,
@@ -85,21 +124,26 @@ pub struct Data {
 #[deriving(Show, Clone)]
 pub struct Closure {
     pub func    : uint,
+    // Index into DispatchData.arities for closures over a multi-arity
+    // function, `func` is unused then.
+    pub arities : Option<uint>,
     pub freevar : Vec<Slot>
 }
 
 #[deriving(Decodable, Show, Clone)]
 pub struct CljType {
-    name:String,
-    nr:uint,
-    size:uint,
-    fields:Fields
+    pub name:String,
+    pub nr:uint,
+    pub size:uint,
+    pub fields:Fields
 }
 
 #[deriving(Show, Clone)]
-struct CljObject {
+pub struct CljObject {
     pub cljtype:uint,
-    pub fields:RawSlots
+    pub fields:RawSlots,
+    // Fields INITFIELD already wrote, each one is initialized only once.
+    pub initialized:Vec<bool>
 }
 
 #[deriving(Decodable, Show, Clone)]
@@ -109,22 +153,90 @@ pub struct CljField {
     pub mutable:bool 
 }
 
-#[deriving(Show)]
+// The bodies of a multi-arity function by argument count. A call that has
+// no fixed body goes to the variadic one, (fixed args, entry), if it has at
+// least that many arguments.
+#[deriving(Decodable, Show, Clone)]
+pub struct Arities {
+    pub fixed    : HashMap<uint,uint>,
+    pub variadic : Option<(uint, uint)>,
+}
+
+impl Arities {
+    pub fn select(&self, nargs: uint) -> Option<InstrPtr> {
+        match self.fixed.find(&nargs) {
+            Some(entry) => Some(*entry),
+            None => match self.variadic {
+                Some((fixed, entry)) if nargs >= fixed => Some(entry),
+                _ => None
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Vec<InstrPtr> {
+        let mut entries: Vec<InstrPtr> = self.fixed.values().map(|e| *e).collect();
+        entries.extend(self.variadic.iter().map(|&(_, e)| e));
+        entries
+    }
+}
+
+#[deriving(Show, Clone)]
 pub struct DispatchData {
-    pub vtable : VTable
+    pub vtable : VTable,
+    pub arities : ArityTable,
 }
 
+// The instructions start..end were compiled from the form at line and
+// column of file. Entries can nest, the narrowest one wins.
+#[deriving(Decodable, Show, Clone)]
+pub struct LineEntry {
+    pub start  : InstrPtr,
+    pub end    : InstrPtr,
+    pub file   : String,
+    pub line   : uint,
+    pub column : uint,
+}
+
+impl LineEntry {
+    pub fn location(&self) -> String {
+        format!("{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// Exceptions thrown by the instructions start..end continue at target with
+// the exception in slot, if it is an object of catch_type. Without a
+// catch_type the handler catches everything. The first matching entry
+// wins, so inner handlers come first.
+#[deriving(Decodable, Show, Clone)]
+pub struct Handler {
+    pub start      : InstrPtr,
+    pub end        : InstrPtr,
+    pub target     : InstrPtr,
+    pub catch_type : Option<uint>,
+    pub slot       : uint,
+}
+
+#[deriving(Clone)]
 pub struct Code {
     pub ip : InstrPtr,
     pub func : CFunc,
+    // `func` decoded by the loaders, this is what the interpreter executes.
+    pub ops : Vec<Op>,
+    // Names of functions by the index of their FUNCF or FUNCV.
+    pub names : HashMap<InstrPtr, String>,
+    pub lines : Vec<LineEntry>,
+    pub handlers : Vec<Handler>,
 }
 
 #[deriving(Show, Clone)]
 pub struct Slots {
     pub base : BasePtr,
     pub slot : Vec<Slot>,
+    // One past the highest slot written, every slot from here on is Nil.
+    pub top  : uint,
 }
 
+#[deriving(Clone)]
 pub struct Context {
     pub base : BasePtr,
     pub ip : InstrPtr,
@@ -132,34 +244,160 @@ pub struct Context {
 
 type Stack = Vec<Context>;
 
+pub struct VmOptions {
+    // Collect after this many heap allocations, 0 disables collection.
+    pub gc_threshold : uint,
+    // Print a line to stderr for every collection.
+    pub gc_report : bool,
+    // Compile functions to native code once they are hot.
+    pub jit : bool,
+    // Calls plus loop iterations after which a function is compiled.
+    pub jit_threshold : uint,
+    // Record and run traces of hot loops.
+    pub trace_jit : bool,
+    // Iterations after which a loop is recorded.
+    pub trace_jit_threshold : uint,
+    // Limit for the slot stack, it grows on demand up to this many slots.
+    pub max_slots : uint,
+    // Limit for the number of nested calls.
+    pub max_depth : uint,
+}
+
 pub struct Vm {
     pub stack : Stack,
     pub slots : Slots,
     pub data  : Data,
     pub code  : Code,
     pub dd    : DispatchData,
-    pub symbol_table : HashMap<String, TopLevelBinding>
+    pub symbol_table : HashMap<String, TopLevelBinding>,
+    pub heap  : Heap,
+    pub opts  : VmOptions,
+    pub debugger : Option<Debugger>,
+    pub tracer : Option<Tracer>,
+    pub profiler : Option<Profiler>,
+    pub jit : Jit,
+    pub tracejit : TraceJit,
+    // The type runtime errors are thrown as.
+    pub error_type : uint,
 }
 
-static VM_MAX_SLOTS : uint = 64000u;
+// Slots allocated up front, the stack grows from there.
+static VM_INITIAL_SLOTS : uint = 4096u;
+
+// Every frame gets this many slots, enough for the 8 bit slot operands and
+// the slots RET and builtins clear past them.
+static VM_FRAME_SLOTS : uint = 512u;
+
+static VM_DEFAULT_MAX_SLOTS : uint = 1000000u;
+
+static VM_DEFAULT_MAX_DEPTH : uint = 100000u;
+
+static GC_DEFAULT_THRESHOLD : uint = 10000u;
+
+static JIT_DEFAULT_THRESHOLD : uint = 1000u;
+
+static TRACE_JIT_DEFAULT_THRESHOLD : uint = 50u;
+
+impl Default for VmOptions {
+    fn default() -> VmOptions {
+        VmOptions {
+            gc_threshold : GC_DEFAULT_THRESHOLD,
+            gc_report : false,
+            jit : false,
+            jit_threshold : JIT_DEFAULT_THRESHOLD,
+            trace_jit : false,
+            trace_jit_threshold : TRACE_JIT_DEFAULT_THRESHOLD,
+            max_slots : VM_DEFAULT_MAX_SLOTS,
+            max_depth : VM_DEFAULT_MAX_DEPTH,
+        }
+    }
+}
 
 impl CljType {
     pub fn alloc(&self) -> CljObject {
 
-        let mut obj = CljObject { cljtype: self.nr, fields: vec![] };
+        let mut obj = CljObject { cljtype: self.nr, fields: vec![], initialized: vec![] };
 
         for _ in range(0, self.size) {
             obj.fields.push(Nil);
+            obj.initialized.push(false);
         }
         obj
     }
 }
 
+impl Code {
+    pub fn new(func: CFunc) -> Code {
+        Code {
+            ip : 0,
+            func : func,
+            ops : vec![],
+            names : HashMap::new(),
+            lines : vec![],
+            handlers : vec![],
+        }
+    }
+
+    // Decodes `func` into `ops`, or returns the index and opcode of the
+    // first invalid instruction.
+    pub fn decode(&mut self) -> Result<(), (InstrPtr, u8)> {
+        self.ops = try!(decode::predecode(self.func.as_slice()));
+        Ok(())
+    }
+
+    // The source form the instruction at `ip` was compiled from.
+    pub fn line_entry(&self, ip: InstrPtr) -> Option<&LineEntry> {
+        self.lines.iter().filter(|e| e.start <= ip && ip < e.end)
+                         .min_by(|e| e.end - e.start)
+    }
+
+    // The handler for an exception thrown by the instruction at `ip`.
+    pub fn handler(&self, ip: InstrPtr, exc: &Slot) -> Option<&Handler> {
+        self.handlers.iter().find(|h| {
+            h.start <= ip && ip < h.end && exception::matches(h.catch_type, exc)
+        })
+    }
+
+    // The name of the function containing `ip`.
+    pub fn function_name(&self, ip: InstrPtr) -> Option<&String> {
+        self.function_entry(ip).and_then(|entry| self.names.find(&entry))
+    }
+
+    // The frame of a stack trace for an instruction of the function
+    // containing it.
+    pub fn stack_frame(&self, ip: InstrPtr) -> StackFrame {
+        let func = if ip < self.func.len() { self.function_entry(ip) } else { None };
+        StackFrame {
+            ip   : ip,
+            func : func,
+            name : func.and_then(|f| self.names.find(&f)).map(|n| n.clone()),
+            source : self.line_entry(ip).map(|e| e.location()),
+        }
+    }
+
+    // The FUNCF or FUNCV instruction of the function containing `ip`.
+    pub fn function_entry(&self, ip: InstrPtr) -> Option<InstrPtr> {
+        range(0, ip + 1).rev().find(|&i| {
+            match self.func[i].decode() {
+                Ok(FUNCF) | Ok(FUNCV) => true,
+                _ => false
+            }
+        })
+    }
+}
+
+impl Data {
+    pub fn find_type(&self, nr: uint) -> Option<&CljType> {
+        self.ctype.iter().find(|t| t.nr == nr)
+    }
+}
+
 impl Slots {
     pub fn new() -> Slots {
         Slots {
             base : 0,
-            slot : Vec::from_fn(VM_MAX_SLOTS, |_| Nil),
+            slot : Vec::from_fn(VM_INITIAL_SLOTS, |_| Nil),
+            top  : 0,
         }
     }
 }
@@ -167,18 +405,48 @@ impl Slots {
 impl Vm {
     pub fn new(data: Data, code: Code, dd : DispatchData) -> Vm
     {
+        let mut data = data;
+        let error_type = exception::register_error_type(&mut data);
+
         Vm {
             stack : vec![],
             slots : Slots::new(),
             code  : code,
             data  : data,
             dd    : dd,
-            symbol_table : HashMap::new()
+            symbol_table : HashMap::new(),
+            heap  : Heap::new(),
+            opts  : Default::default(),
+            debugger : None,
+            tracer : None,
+            profiler : None,
+            jit : Jit::new(),
+            tracejit : TraceJit::new(),
+            error_type : error_type,
         }
     }
 
-    pub fn start(&mut self) {
-        let mut instr = self.fetch(0);
+    pub fn start(&mut self) -> VmResult<()> {
+        // The loaders decode the code, only Code built by hand is not.
+        if self.code.ops.len() != self.code.func.len() {
+            match self.code.decode() {
+                Ok(()) => (),
+                Err((ip, opcode)) => {
+                    self.code.ip = ip;
+                    return Err(self.error(InvalidOpcode(opcode), vec![]));
+                }
+            }
+        }
+
+        // Nothing runs unverified, the interpreter trusts the operands.
+        match verify::verify(&self.data, &self.code, &self.dd) {
+            Ok(()) => (),
+            Err(errors) => {
+                self.code.ip = errors[0].ip;
+                let msgs = errors.iter().map(|err| err.to_string()).collect();
+                return Err(self.error(InvalidBytecode(msgs), vec![]));
+            }
+        }
 
         self.symbol_table.insert("println".to_string(),
                                   TopLevelBinding {
@@ -187,14 +455,112 @@ impl Vm {
                                   }
                                   );
 
-        while instr.decode() != EXIT {
-            let next = instr.execute(self);
-            //println!("{}: {}", instr, self.slots.slot[..10]);
-            
-            instr = next;
+        let op = self.fetch(0);
+
+        if self.debugger.is_some() || self.tracer.is_some() || self.profiler.is_some() {
+            self.run_hooked(op)
+        } else {
+            self.run(op)
         }
     }
 
+    // The hot loop, ops are decoded once when loading so the dispatch in
+    // `execute` is a single match on the OpCode.
+    fn run(&mut self, mut op: Op) -> VmResult<()> {
+        while op.op != EXIT {
+            if self.tracejit.recording.is_some() {
+                tracejit::record(self, &op);
+            }
+
+            op = match op.execute(self) {
+                Ok(op) => op,
+                Err(err) => try!(exception::throw(self, err))
+            };
+
+            if self.opts.gc_threshold > 0 &&
+               self.heap.allocated() >= self.opts.gc_threshold {
+                self.collect_garbage();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Same as `run`, with the debugger, tracer and profiler hooks around
+    // every instruction.
+    fn run_hooked(&mut self, mut op: Op) -> VmResult<()> {
+        while op.op != EXIT {
+            let instr = self.code.func[self.code.ip];
+
+            if self.debugger.is_some() {
+                let mut dbg = self.debugger.take().unwrap();
+                let action = dbg.hook(self, &instr);
+                self.debugger = Some(dbg);
+
+                match action {
+                    debug::Resume => (),
+                    debug::Quit => return Ok(())
+                }
+            }
+
+            let entry = match self.tracer {
+                Some(ref tracer) => tracer.before(self, &instr),
+                None => None
+            };
+
+            if self.tracejit.recording.is_some() {
+                tracejit::record(self, &op);
+            }
+
+            let depth = self.stack.len();
+            match self.profiler {
+                Some(ref mut profiler) => profiler.count(&instr),
+                None => ()
+            }
+
+            op = match op.execute(self) {
+                Ok(op) => op,
+                Err(err) => try!(exception::throw(self, err))
+            };
+
+            match self.profiler {
+                Some(ref mut profiler) => profiler.update(depth, self.stack.len(),
+                                                          self.code.ip),
+                None => ()
+            }
+
+            match entry {
+                Some(entry) => {
+                    let mut tracer = self.tracer.take().unwrap();
+                    tracer.after(self, &instr, entry);
+                    self.tracer = Some(tracer);
+                }
+                None => ()
+            }
+
+            if self.opts.gc_threshold > 0 &&
+               self.heap.allocated() >= self.opts.gc_threshold {
+                self.collect_garbage();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn collect_garbage(&mut self) -> gc::GcStats {
+        let start = time::precise_time_ns();
+        let stats = gc::collect(self);
+
+        if self.opts.gc_report {
+            let _ = writeln!(&mut io::stderr(),
+                             "gc #{}: {} live, {} freed in {} us",
+                             self.heap.collections, stats.live, stats.freed,
+                             (time::precise_time_ns() - start) / 1000);
+        }
+
+        stats
+    }
+
     pub fn get_context(&self) -> Context {
         Context {
             base : self.slots.base,
@@ -206,12 +572,65 @@ impl Vm {
         self.slots.base = ctx.base;
         self.code.ip = ctx.ip;
     }
+
+    // Makes sure the slot stack has at least `len` slots, growing it by
+    // doubling up to `opts.max_slots`.
+    pub fn grow_slots(&mut self, len: uint) -> VmResult<()> {
+        // Checked first, the initial slots may already be past a lower limit.
+        if len > self.opts.max_slots {
+            return Err(self.error(StackOverflow(self.stack.len()), vec![]));
+        }
+        let cur = self.slots.slot.len();
+        if len <= cur {
+            return Ok(());
+        }
+
+        let new_len = cmp::min(cmp::max(len, cur * 2), self.opts.max_slots);
+        self.slots.slot.grow(new_len - cur, &Nil);
+        Ok(())
+    }
+
+    // Makes sure the current frame has `n` slots, for D operands and
+    // argument counts that can reach past the slots every frame gets.
+    pub fn reserve_slots(&mut self, n: uint) -> VmResult<()> {
+        let len = self.slots.base + n;
+        self.grow_slots(len)
+    }
+
+    // Checks the limits before a call enters a frame at `base`.
+    pub fn enter_frame(&mut self, base: BasePtr) -> VmResult<()> {
+        if self.stack.len() >= self.opts.max_depth {
+            return Err(self.error(StackOverflow(self.stack.len()), vec![]));
+        }
+        self.grow_slots(base + VM_FRAME_SLOTS)
+    }
+
+    // The current instruction followed by the CALL of every active frame,
+    // innermost first.
+    pub fn backtrace(&self) -> Vec<Context> {
+        let mut frames = vec![self.get_context()];
+        frames.extend(self.stack.iter().rev().map(|ctx| ctx.clone()));
+        frames
+    }
+
+    pub fn error(&self, kind: ErrorKind, slots: Vec<Slot>) -> VmError {
+        VmError {
+            kind  : kind,
+            op    : self.code.func.as_slice().get(self.code.ip).and_then(|i| i.decode().ok()),
+            ip    : self.code.ip,
+            slots : slots,
+            backtrace : self.backtrace(),
+        }
+    }
 }
 
 impl<I:ToPrimitive> IndexMut<I, Slot> for Slots {
     fn index_mut(&mut self, index: &I) -> &mut Slot {
-        let index = index.to_uint().unwrap();
-        self.slot.get_mut(self.base + index)
+        let index = self.base + index.to_uint().unwrap();
+        if index >= self.top {
+            self.top = index + 1;
+        }
+        self.slot.get_mut(index)
     }
 }
 
@@ -246,6 +665,18 @@ impl Slots {
         self[index] = val
     }
 
+    // Clears the slots of the current frame from `index` on, only the ones
+    // below the high-water mark can hold anything.
+    pub fn truncate<I:ToPrimitive>(&mut self, index: I) {
+        let from = self.base + index.to_uint().unwrap();
+        for i in range(from, self.top) {
+            *self.slot.get_mut(i) = Nil;
+        }
+        if from < self.top {
+            self.top = from;
+        }
+    }
+
     //pub fn mov<I:ToPrimitive>(&mut self, dst_index: I, src_index: I) {
     //    let src_slot = self.load(src_index);
     //    self.store(dst_index, src_slot);
@@ -253,13 +684,18 @@ impl Slots {
 }
 
 impl OpCode {
+    pub fn from_name(name: &str) -> Option<OpCode> {
+        range(0u, 256).filter_map(|i| FromPrimitive::from_uint(i))
+                      .find(|op: &OpCode| op.to_string().as_slice() == name)
+    }
+
     pub fn ty(self) -> InstrType {
         match self {
             ADDVV|SUBVV|MULVV|DIVVV|MODVV|POWVV|
             ISLT|ISGE|ISLE|ISGT|ISEQ|ISNEQ|
             APPLY|
             NSSETS|
-            GETFIELD|SETFIELD|
+            GETFIELD|SETFIELD|INITFIELD|
             LOOP|BULKMOV|
             NEWARRAY|GETARRAY|SETARRAY
                 => TyABC,
@@ -267,10 +703,11 @@ impl OpCode {
             NSGETS|
             MOV|NOT|NEG|
             JUMP|JUMPF|JUMPT|
-            CALL|RET|
-            FNEW| VFNEW|
+            CALL|TCALL|RET|
+            FNEW| VFNEW| MFNEW|
             DROP|TRANC|UCLO|
             FUNCF|FUNCV|
+            THROW|
             GETFREEVAR|
             ALLOC|
             EXIT
@@ -281,6 +718,25 @@ impl OpCode {
 
 impl fmt::Show for Slot {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_seen(f, &mut vec![])
+    }
+}
+
+// Prints `slots` as a list, `seen` is passed on to every element.
+fn fmt_slots(slots: &[Slot], f: &mut fmt::Formatter,
+             seen: &mut Vec<*const ()>) -> fmt::Result {
+    try!(write!(f, "["));
+    for (i, val) in slots.iter().enumerate() {
+        if i > 0 { try!(write!(f, ", ")); }
+        try!(val.fmt_seen(f, seen));
+    }
+    write!(f, "]")
+}
+
+impl Slot {
+    // Arrays and objects can contain themselves, `seen` holds the ones that
+    // are being printed and a nested one is printed as [...] or {...}.
+    fn fmt_seen(&self, f: &mut fmt::Formatter, seen: &mut Vec<*const ()>) -> fmt::Result {
         match *self {
             Nil => write!(f, "Nil"),
             Builtin(_) => write!(f, ""),
@@ -290,10 +746,48 @@ impl fmt::Show for Slot {
             Str(ref x) =>  x.fmt(f),
             Key(ref x) =>  x.fmt(f),
             Func(ref x) =>  x.fmt(f),
-            VFunc(ref x) =>  x.fmt(f),            
-            Obj(ref x) =>  x.fmt(f),
+            VFunc(ref x) =>  x.fmt(f),
+            MFunc(ref x) =>  x.fmt(f),
             CType(ref x) =>  x.fmt(f),
-            SCC(ref x) =>  x.fmt(f)
+            Obj(ref x) => {
+                let addr = &**x as *const RefCell<CljObject> as *const ();
+                if seen.contains(&addr) {
+                    return write!(f, "{{...}}");
+                }
+                seen.push(addr);
+                let obj = x.borrow();
+                try!(write!(f, "CljObject {{ cljtype: {}, fields: ", obj.cljtype));
+                try!(fmt_slots(obj.fields.as_slice(), f, seen));
+                seen.pop();
+                write!(f, " }}")
+            }
+            SCC(ref x) => {
+                try!(write!(f, "Closure {{ func: {}, arities: {}, freevar: ",
+                            x.func, x.arities));
+                try!(fmt_slots(x.freevar.as_slice(), f, seen));
+                write!(f, " }}")
+            }
+            Array(ref x) => {
+                let addr = &**x as *const RefCell<Vec<Slot>> as *const ();
+                if seen.contains(&addr) {
+                    return write!(f, "[...]");
+                }
+                seen.push(addr);
+                try!(fmt_slots(x.borrow().as_slice(), f, seen));
+                seen.pop();
+                Ok(())
+            }
+        }
+    }
+
+    // Reference identity for heap values, like `identical?` on the JVM.
+    pub fn is_identical(&self, other: &Slot) -> bool {
+        match (self, other) {
+            (&Obj(ref x), &Obj(ref y)) =>
+                &**x as *const RefCell<CljObject> == &**y as *const RefCell<CljObject>,
+            (&Array(ref x), &Array(ref y)) =>
+                &**x as *const RefCell<Vec<Slot>> == &**y as *const RefCell<Vec<Slot>>,
+            _ => false
         }
     }
 }
@@ -0,0 +1,166 @@
+// A tiny x86-64 assembler, just the instructions the baseline JIT needs.
+// All memory operands are [rdi + disp32], rdi holds the native frame.
+
+pub enum Reg {
+    RAX = 0,
+    RCX = 1,
+}
+
+pub enum Cond {
+    CondE  = 0x4,
+    CondNE = 0x5,
+    CondL  = 0xc,
+    CondGE = 0xd,
+    CondLE = 0xe,
+    CondG  = 0xf,
+}
+
+pub struct Label(uint);
+
+pub struct Asm {
+    buf    : Vec<u8>,
+    labels : Vec<Option<uint>>,
+    // (position of a rel32, label it refers to)
+    fixups : Vec<(uint, uint)>,
+}
+
+static REX_W : u8 = 0x48;
+
+fn modrm_rdi_disp32(reg: u8) -> u8 {
+    0x80 | (reg << 3) | 7
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        Asm { buf: vec![], labels: vec![], fixups: vec![] }
+    }
+
+    pub fn pos(&self) -> uint {
+        self.buf.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: &Label) {
+        let Label(l) = *label;
+        *self.labels.get_mut(l) = Some(self.buf.len());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.buf.push_all(bytes);
+    }
+
+    fn imm32(&mut self, v: i32) {
+        for i in range(0u, 4) {
+            self.buf.push((v >> (8 * i)) as u8);
+        }
+    }
+
+    fn rel32(&mut self, label: &Label) {
+        let Label(l) = *label;
+        self.fixups.push((self.buf.len(), l));
+        self.imm32(0);
+    }
+
+    // mov reg, [rdi + disp]
+    pub fn load(&mut self, reg: Reg, disp: i32) {
+        self.emit(&[REX_W, 0x8b, modrm_rdi_disp32(reg as u8)]);
+        self.imm32(disp);
+    }
+
+    // mov [rdi + disp], reg
+    pub fn store(&mut self, disp: i32, reg: Reg) {
+        self.emit(&[REX_W, 0x89, modrm_rdi_disp32(reg as u8)]);
+        self.imm32(disp);
+    }
+
+    // mov qword [rdi + disp], imm (sign extended)
+    pub fn store_imm(&mut self, disp: i32, imm: i32) {
+        self.emit(&[REX_W, 0xc7, modrm_rdi_disp32(0)]);
+        self.imm32(disp);
+        self.imm32(imm);
+    }
+
+    // cmp qword [rdi + disp], imm (sign extended)
+    pub fn cmp_mem_imm(&mut self, disp: i32, imm: i8) {
+        self.emit(&[REX_W, 0x83, modrm_rdi_disp32(7)]);
+        self.imm32(disp);
+        self.emit(&[imm as u8]);
+    }
+
+    // cmp rax, imm (sign extended)
+    pub fn cmp_rax_imm(&mut self, imm: i8) {
+        self.emit(&[REX_W, 0x83, 0xf8, imm as u8]);
+    }
+
+    // cmp rax, rcx
+    pub fn cmp_rax_rcx(&mut self) {
+        self.emit(&[REX_W, 0x39, 0xc8]);
+    }
+
+    // add rax, rcx
+    pub fn add_rax_rcx(&mut self) {
+        self.emit(&[REX_W, 0x01, 0xc8]);
+    }
+
+    // sub rax, rcx
+    pub fn sub_rax_rcx(&mut self) {
+        self.emit(&[REX_W, 0x29, 0xc8]);
+    }
+
+    // imul rax, rcx
+    pub fn imul_rax_rcx(&mut self) {
+        self.emit(&[REX_W, 0x0f, 0xaf, 0xc1]);
+    }
+
+    // neg rax
+    pub fn neg_rax(&mut self) {
+        self.emit(&[REX_W, 0xf7, 0xd8]);
+    }
+
+    // setcc al; movzx eax, al
+    pub fn setcc_rax(&mut self, cond: Cond) {
+        self.emit(&[0x0f, 0x90 | cond as u8, 0xc0]);
+        self.emit(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    // mov rax, imm64
+    pub fn mov_rax_imm64(&mut self, imm: i64) {
+        self.emit(&[REX_W, 0xb8]);
+        for i in range(0u, 8) {
+            self.buf.push((imm >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn jmp(&mut self, label: &Label) {
+        self.emit(&[0xe9]);
+        self.rel32(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: &Label) {
+        self.emit(&[0x0f, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+
+    // mov eax, val; ret
+    pub fn ret_imm(&mut self, val: u32) {
+        self.emit(&[0xb8]);
+        self.imm32(val as i32);
+        self.emit(&[0xc3]);
+    }
+
+    // Resolves the jumps, every label used must have been bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(pos, l) in self.fixups.iter() {
+            let target = self.labels[l].expect("unbound label");
+            let rel = target as i32 - (pos + 4) as i32;
+            for i in range(0u, 4) {
+                *self.buf.get_mut(pos + i) = (rel >> (8 * i)) as u8;
+            }
+        }
+        self.buf
+    }
+}
@@ -0,0 +1,54 @@
+extern crate clojit;
+
+use clojit::asm;
+
+use support::{run, slot};
+
+mod support;
+
+// Constants are pooled, jump offsets come from labels.
+static SUM : &'static str = "
+.const limit 10
+        CSHORT 1 0
+        CSHORT 2 0
+        CSHORT 3 1
+        CINT   4 limit
+loop:   ISLT   5 2 4
+        JUMPF  5 done
+        ADDVV  2 2 3
+        ADDVV  1 1 2
+        JUMP   0 loop
+done:   CSTR   6 \"sum\"
+        CFLOAT 7 2.5
+        EXIT
+";
+
+#[test]
+fn assembled_program_runs() {
+    let vm = run(SUM);
+    assert_eq!(slot(&vm, 1), "55".to_string());
+    assert_eq!(slot(&vm, 6), "sum".to_string());
+    assert_eq!(slot(&vm, 7), "2.5".to_string());
+}
+
+#[test]
+fn constants_are_pooled_once() {
+    let (data, _, _) = asm::assemble("CINT 1 7\nCINT 2 7\nCINT 3 8\nEXIT").unwrap();
+    assert_eq!(data.cint, vec![7, 8]);
+}
+
+fn asm_error(src: &str) -> String {
+    match asm::assemble(src) {
+        Ok(_) => panic!("assembled: {}", src),
+        Err(err) => err.to_string()
+    }
+}
+
+#[test]
+fn errors_name_the_line() {
+    assert_eq!(asm_error("CSHORT 1 0\nFOO 1 2"), "line 2: unknown opcode FOO".to_string());
+    assert_eq!(asm_error("JUMP 0 nowhere"), "line 1: undefined name nowhere".to_string());
+    assert_eq!(asm_error("CSHORT 256 0"), "line 1: operand 256 does not fit in 8 bits".to_string());
+    assert_eq!(asm_error("a: EXIT\na: EXIT"), "line 2: label a defined twice".to_string());
+    assert_eq!(asm_error("CTYPE 1 Nope"), "line 1: unknown type Nope".to_string());
+}
@@ -0,0 +1,126 @@
+extern crate clojit;
+
+use std::io;
+use std::io::TempDir;
+
+use clojit::load;
+use clojit::binary;
+use clojit::vm::Vm;
+
+use support::slot;
+
+mod support;
+
+// Every section of the format: constants, types, arities, names, lines and
+// handlers.
+static PROGRAM : &'static str = r#"{
+    "bytecode": [
+        {"op": "MFNEW",  "a": 2, "d": 0},
+        {"op": "CSHORT", "a": 3, "d": 5},
+        {"op": "CALL",   "a": 1, "d": 1},
+        {"op": "CINT",   "a": 4, "d": 0},
+        {"op": "EXIT"},
+        {"op": "FUNCF",  "a": 1},
+        {"op": "RET",    "a": 2}
+    ],
+    "CINT": [7],
+    "CFLOAT": [1.5],
+    "CSTR": ["s"],
+    "CKEY": ["k"],
+    "vtable": {},
+    "arities": {"0": {"fixed": {"1": 5}, "variadic": null}},
+    "names": {"5": "ident"},
+    "lines": [{"start": 5, "end": 7, "file": "core.clj", "line": 3, "column": 1}],
+    "handlers": [{"start": 0, "end": 4, "target": 4, "catch_type": null, "slot": 6}],
+    "types": [{"name": "Point", "nr": 0, "size": 2, "fields": [
+        {"name": "x", "offset": 0, "mutable": false},
+        {"name": "y", "offset": 1, "mutable": true}
+    ]}]
+}"#;
+
+fn write_file(path: &Path, bytes: &[u8]) {
+    io::File::create(path).write(bytes).unwrap();
+}
+
+// JSON to CLJB and back. The reader only accepts the current VERSION, so
+// that is the one version to round-trip.
+#[test]
+fn cljb_round_trip() {
+    let dir = TempDir::new("clojit-test").unwrap();
+    let json = dir.path().join("program.json");
+    let cljb = dir.path().join("program.cljb");
+    write_file(&json, PROGRAM.as_bytes());
+
+    let (data, code, dd) = load::load(&json).unwrap();
+    binary::write(&cljb, &data, &code, &dd).unwrap();
+    assert!(binary::is_binary(&cljb).unwrap());
+    let (data2, code2, dd2) = load::load(&cljb).unwrap();
+
+    assert_eq!(data2.cint, data.cint);
+    assert_eq!(data2.cfloat, data.cfloat);
+    assert_eq!(data2.cstr, data.cstr);
+    assert_eq!(data2.ckey, data.ckey);
+    assert_eq!(data2.ctype.to_string(), data.ctype.to_string());
+    assert_eq!(code2.func, code.func);
+    assert_eq!(code2.names, code.names);
+    assert_eq!(code2.lines.to_string(), code.lines.to_string());
+    assert_eq!(code2.handlers.to_string(), code.handlers.to_string());
+    assert_eq!(dd2.vtable, dd.vtable);
+    assert_eq!(dd2.arities.to_string(), dd.arities.to_string());
+
+    let mut vm = Vm::new(data2, code2, dd2);
+    vm.start().unwrap();
+    assert_eq!(slot(&vm, 1), "5".to_string());
+    assert_eq!(slot(&vm, 4), "7".to_string());
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let dir = TempDir::new("clojit-test").unwrap();
+    let json = dir.path().join("program.json");
+    let cljb = dir.path().join("program.cljb");
+    write_file(&json, PROGRAM.as_bytes());
+
+    let (data, code, dd) = load::load(&json).unwrap();
+    binary::write(&cljb, &data, &code, &dd).unwrap();
+    let bytes = io::File::open(&cljb).read_to_end().unwrap();
+
+    for &version in [binary::VERSION - 1, binary::VERSION + 1].iter() {
+        let mut patched = bytes.clone();
+        *patched.get_mut(4) = version as u8;
+        *patched.get_mut(5) = (version >> 8) as u8;
+        write_file(&cljb, patched.as_slice());
+
+        match load::load(&cljb) {
+            Ok(_) => panic!("loaded version {}", version),
+            Err(err) => assert!(err.as_slice().contains("unsupported bytecode version"))
+        }
+    }
+}
+
+// Lengths from a truncated or hostile file run into the end of the file
+// instead of allocating what they ask for.
+#[test]
+fn huge_lengths_are_errors() {
+    let dir = TempDir::new("clojit-test").unwrap();
+    let cljb = dir.path().join("program.cljb");
+
+    let mut header = binary::MAGIC.to_vec();
+    header.push(binary::VERSION as u8);
+    header.push((binary::VERSION >> 8) as u8);
+
+    // 0xffffffff CINT constants.
+    let mut consts = header.clone();
+    consts.push_all([0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+    write_file(&cljb, consts.as_slice());
+    assert!(load::load(&cljb).is_err());
+
+    // No CINT and CFLOAT constants, one CSTR constant of 0xffffffff bytes.
+    let mut string = header.clone();
+    string.push_all([0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x61]);
+    write_file(&cljb, string.as_slice());
+    match load::load(&cljb) {
+        Ok(_) => panic!("loaded a truncated string"),
+        Err(err) => assert!(err.as_slice().contains("truncated string"))
+    }
+}
@@ -0,0 +1,134 @@
+extern crate clojit;
+
+use support::{run, run_with, slot, error_kind};
+
+mod support;
+
+// One fixed and one variadic body, the variadic one gets the arguments past
+// its fixed ones as an array.
+static ARITIES : &'static str = "
+.arity 0 1 one
+.arity 0 2+ many
+        MFNEW  2 0
+        CSHORT 3 5
+        CALL   1 1
+        MFNEW  6 0
+        CSHORT 7 1
+        CSHORT 8 2
+        CSHORT 9 3
+        CALL   5 3
+        EXIT
+
+.func one 1
+        RET    2
+
+.funcv many 2
+        RET    4
+";
+
+#[test]
+fn multi_arity_selects_body() {
+    let vm = run(ARITIES);
+    assert_eq!(slot(&vm, 1), "5".to_string());
+    assert_eq!(slot(&vm, 5), "[3]".to_string());
+}
+
+#[test]
+fn multi_arity_without_body_fails() {
+    let src = "
+.arity 0 1 one
+        MFNEW  2 0
+        CALL   1 0
+        EXIT
+
+.func one 1
+        RET    2
+";
+    let (_, res) = run_with(src, |_| ());
+    assert_eq!(error_kind(res), "NoMatchingArity(0, 0)".to_string());
+}
+
+// FUNCF takes exactly its arity.
+#[test]
+fn fixed_arity_mismatch_fails() {
+    let src = "
+        FNEW   2 f
+        CSHORT 3 1
+        CSHORT 4 2
+        CALL   1 2
+        EXIT
+
+.func f 1
+        RET    2
+";
+    let (_, res) = run_with(src, |_| ());
+    assert_eq!(error_kind(res), "ArityMismatch(2, 1)".to_string());
+}
+
+// Returns the rest argument of a FUNCV with one fixed argument, called with
+// the arguments in ARGS.
+fn rest_of(args: &[int]) -> String {
+    let mut src = "        FNEW   2 rest\n".to_string();
+    for (i, arg) in args.iter().enumerate() {
+        src.push_str(format!("        CSHORT {} {}\n", 3 + i, arg).as_slice());
+    }
+    src.push_str(format!("        CALL   1 {}
+        EXIT
+
+.funcv rest 1
+        RET    3
+", args.len()).as_slice());
+    slot(&run(src.as_slice()), 1)
+}
+
+#[test]
+fn variadic_without_extra_args_gets_nil() {
+    assert_eq!(rest_of(&[5]), "Nil".to_string());
+}
+
+#[test]
+fn variadic_packs_extra_args() {
+    assert_eq!(rest_of(&[5, 6, 7, 8]), "[6, 7, 8]".to_string());
+}
+
+// Counts down from 1000 with OP as the recursive call.
+fn count_down(op: &str) -> String {
+    format!("
+        FNEW   2 count
+        CINT   3 1000
+        CALL   1 1
+        EXIT
+
+.func count 1
+        CSHORT 3 0
+        ISGT   4 2 3
+        JUMPF  4 done
+        CSHORT 3 1
+        SUBVV  7 2 3
+        FNEW   6 count
+        {}  5 1
+        RET    5
+done:   RET    2
+", op)
+}
+
+#[test]
+fn tail_call_reuses_frame() {
+    let (vm, res) = run_with(count_down("TCALL").as_slice(), |vm| vm.opts.max_depth = 10);
+    res.unwrap();
+    assert!(vm.stack.is_empty());
+    assert_eq!(slot(&vm, 1), "0".to_string());
+}
+
+#[test]
+fn call_hits_depth_limit() {
+    let (_, res) = run_with(count_down("CALL").as_slice(), |vm| vm.opts.max_depth = 10);
+    assert_eq!(error_kind(res), "StackOverflow(10)".to_string());
+}
+
+// The limit holds below the slots the Vm starts with.
+#[test]
+fn call_hits_slot_limit() {
+    let (_, res) = run_with(count_down("CALL").as_slice(), |vm| vm.opts.max_slots = 1000);
+    assert!(error_kind(res).as_slice().starts_with("StackOverflow("));
+}
@@ -0,0 +1,137 @@
+extern crate clojit;
+
+use support::{run, run_with, slot, error_kind};
+
+mod support;
+
+// The first handler covering the THROW is for another type, the second one
+// catches it.
+static MATCH_BY_TYPE : &'static str = "
+.type Ex msg
+.type Other msg
+        CTYPE  3 Other
+        ALLOC  4 3
+try:    THROW  4
+end:    EXIT
+ex:     CSHORT 5 1
+        EXIT
+other:  CSHORT 5 2
+        EXIT
+.catch try end ex 6 Ex
+.catch try end other 6 Other
+";
+
+#[test]
+fn handler_matches_by_type() {
+    let vm = run(MATCH_BY_TYPE);
+    assert_eq!(slot(&vm, 5), "2".to_string());
+}
+
+// A runtime error is caught as a VmError, its message is without the
+// backtrace.
+static RUNTIME_ERROR : &'static str = "
+.type VmError kind message ip
+        CSHORT 1 1
+        CSHORT 2 0
+try:    MODVV  3 1 2
+end:    EXIT
+caught: GETFIELD 5 4 0
+        GETFIELD 6 4 1
+        EXIT
+.catch try end caught 4 VmError
+";
+
+#[test]
+fn runtime_error_is_caught_as_vm_error() {
+    let vm = run(RUNTIME_ERROR);
+    assert_eq!(slot(&vm, 5), "DivideByZero".to_string());
+    assert!(slot(&vm, 6).as_slice().starts_with("DivideByZero at 2"));
+    assert!(!slot(&vm, 6).as_slice().contains("\n"));
+}
+
+// A field of a declared VmError past the size of the type stays unset
+// instead of writing past the object.
+#[test]
+fn vm_error_field_past_size_is_skipped() {
+    let (vm, res) = run_with(RUNTIME_ERROR, |vm| {
+        let i = vm.data.ctype.iter().position(|t| t.name.as_slice() == "VmError").unwrap();
+        vm.data.ctype.get_mut(i).fields.get_mut(1).offset = 3;
+    });
+    res.unwrap();
+    assert_eq!(slot(&vm, 5), "DivideByZero".to_string());
+    assert_eq!(slot(&vm, 6), "Nil".to_string());
+}
+
+// Thrown two calls deep, the handler in main runs in the frame of main.
+static UNWIND : &'static str = "
+.type Ex msg
+        FNEW   2 outer
+try:    CALL   1 0
+end:    EXIT
+caught: CSHORT 3 1
+        EXIT
+.catch try end caught 4 Ex
+
+.func outer 0
+        FNEW   3 inner
+        CALL   2 0
+        RET    0
+
+.func inner 0
+        CTYPE  3 Ex
+        ALLOC  4 3
+        THROW  4
+";
+
+#[test]
+fn throw_unwinds_across_frames() {
+    let vm = run(UNWIND);
+    assert_eq!(vm.slots.base, 0);
+    assert!(vm.stack.is_empty());
+    assert_eq!(slot(&vm, 3), "1".to_string());
+}
+
+// A handler without a catch type marks the exception and throws it again,
+// the handler of the caller sees the mark.
+static RETHROW : &'static str = "
+.type Ex ran:mut
+        FNEW   2 inner
+try:    CALL   1 0
+end:    EXIT
+caught: GETFIELD 3 4 0
+        EXIT
+.catch try end caught 4 Ex
+
+.func inner 0
+        CTYPE  3 Ex
+        ALLOC  4 3
+itry:   THROW  4
+finally: CSHORT 5 1
+        SETFIELD 6 0 5
+        THROW  6
+.catch itry finally finally 6
+";
+
+#[test]
+fn rethrow_reaches_outer_handler() {
+    let vm = run(RETHROW);
+    assert_eq!(vm.slots.base, 0);
+    assert_eq!(slot(&vm, 3), "1".to_string());
+}
+
+static UNCAUGHT : &'static str = "
+.type Ex msg
+.type Other msg
+        CTYPE  3 Ex
+        ALLOC  4 3
+try:    THROW  4
+end:    EXIT
+other:  EXIT
+.catch try end other 6 Other
+";
+
+#[test]
+fn uncaught_exception_is_an_error() {
+    let (_, res) = run_with(UNCAUGHT, |_| ());
+    assert_eq!(error_kind(res), "Thrown".to_string());
+}
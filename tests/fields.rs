@@ -0,0 +1,43 @@
+extern crate clojit;
+
+use support::{run, run_with, slot, error_kind};
+
+mod support;
+
+// The constructor sequence of a Point, INITFIELD writes the immutable x.
+static CONSTRUCT : &'static str = "
+.type Point x y:mut
+        CTYPE  1 Point
+        ALLOC  2 1
+        CSHORT 3 4
+        INITFIELD 2 0 3
+        CSHORT 3 5
+        INITFIELD 2 1 3
+        GETFIELD 4 2 0
+        GETFIELD 5 2 1
+        EXIT
+";
+
+#[test]
+fn initfield_writes_immutable_field() {
+    let vm = run(CONSTRUCT);
+    assert_eq!(slot(&vm, 4), "4".to_string());
+    assert_eq!(slot(&vm, 5), "5".to_string());
+}
+
+// An immutable field cannot be initialized a second time after the
+// constructor.
+#[test]
+fn initfield_twice_fails() {
+    let src = "
+.type Point x y:mut
+        CTYPE  1 Point
+        ALLOC  2 1
+        CSHORT 3 4
+        INITFIELD 2 0 3
+        INITFIELD 2 0 3
+        EXIT
+";
+    let (_, res) = run_with(src, |_| ());
+    assert!(error_kind(res).as_slice().starts_with("ImmutableField("));
+}
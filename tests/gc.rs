@@ -0,0 +1,66 @@
+extern crate clojit;
+
+use clojit::vm::{Array, Int, SCC, Closure};
+
+use support::load;
+
+mod support;
+
+// A closure outside of the roots, like one in a builtin's locals, keeps the
+// array in its free vars and the cycle through it alive.
+#[test]
+fn value_held_by_closure_survives() {
+    let mut vm = load("EXIT");
+    let inner = vm.heap.alloc_array(vec![Int(1), Int(2)]);
+    let outer = vm.heap.alloc_array(vec![Array(inner.clone())]);
+    inner.borrow_mut().push(Array(outer.clone()));
+
+    let clos = SCC(Closure { func: 0, arities: None, freevar: vec![Array(outer)] });
+    drop(inner);
+
+    let stats = vm.collect_garbage();
+    assert_eq!(stats.freed, 0);
+
+    match clos {
+        SCC(ref clos) => match clos.freevar[0] {
+            Array(ref outer) => match outer.borrow().as_slice()[0] {
+                Array(ref inner) => assert_eq!(inner.borrow().len(), 3),
+                _ => panic!("outer array was emptied")
+            },
+            _ => panic!("free var changed")
+        },
+        _ => ()
+    }
+}
+
+// A cycle nothing else holds is broken.
+#[test]
+fn unreachable_cycle_is_freed() {
+    let mut vm = load("EXIT");
+    let a = vm.heap.alloc_array(vec![]);
+    let b = vm.heap.alloc_array(vec![Array(a.clone())]);
+    a.borrow_mut().push(Array(b.clone()));
+    let weak = a.downgrade();
+    drop(a);
+    drop(b);
+
+    let stats = vm.collect_garbage();
+    assert_eq!(stats.freed, 2);
+    assert!(weak.upgrade().is_none());
+}
+
+// A cycle through the free vars of a closure stored in the array is broken
+// like a direct one.
+#[test]
+fn cycle_through_closure_is_freed() {
+    let mut vm = load("EXIT");
+    let arr = vm.heap.alloc_array(vec![]);
+    let clos = SCC(Closure { func: 0, arities: None, freevar: vec![Array(arr.clone())] });
+    arr.borrow_mut().push(clos);
+    let weak = arr.downgrade();
+    drop(arr);
+
+    let stats = vm.collect_garbage();
+    assert_eq!(stats.freed, 1);
+    assert!(weak.upgrade().is_none());
+}
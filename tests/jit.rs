@@ -0,0 +1,69 @@
+extern crate clojit;
+
+use support::{run_with, slots};
+
+mod support;
+
+// A hot loop in main. MOV 5 300 reads a slot past the 8 bit range, slot 44
+// holds a value so reading a truncated D would show in slot 5.
+static HOT_LOOP : &'static str = "
+        CSHORT 1 0
+        CSHORT 2 1
+        CINT   3 5000
+        CSHORT 44 7
+loop:   LOOP
+        ISLT   4 1 3
+        JUMPF  4 done
+        ADDVV  1 1 2
+        MOV    5 300
+        NEG    6 1
+        NOT    7 4
+        JUMP   0 loop
+done:   EXIT
+";
+
+fn run(src: &str, jit: bool) -> Vec<String> {
+    let (vm, res) = run_with(src, |vm| {
+        vm.opts.jit = jit;
+        vm.opts.jit_threshold = 10;
+    });
+    res.unwrap();
+    slots(&vm, 8)
+}
+
+#[test]
+fn jit_matches_interpreter() {
+    let interpreted = run(HOT_LOOP, false);
+    let compiled = run(HOT_LOOP, true);
+
+    assert_eq!(interpreted[1], "5000".to_string());
+    assert_eq!(interpreted[5], "Nil".to_string());
+    assert_eq!(compiled, interpreted);
+}
+
+// CSHORT operands are signed, -1 and 0x8000 are negative in native code too.
+static SHORTS : &'static str = "
+        CSHORT 1 0
+        CSHORT 2 1
+        CINT   3 100
+loop:   LOOP
+        ISLT   4 1 3
+        JUMPF  4 done
+        ADDVV  1 1 2
+        CSHORT 5 -1
+        CSHORT 6 32768
+        CSHORT 7 32767
+        JUMP   0 loop
+done:   EXIT
+";
+
+#[test]
+fn jit_shorts_are_signed() {
+    let interpreted = run(SHORTS, false);
+    let compiled = run(SHORTS, true);
+
+    assert_eq!(interpreted[5], "-1".to_string());
+    assert_eq!(interpreted[6], "-32768".to_string());
+    assert_eq!(interpreted[7], "32767".to_string());
+    assert_eq!(compiled, interpreted);
+}
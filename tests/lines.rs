@@ -0,0 +1,38 @@
+extern crate clojit;
+
+use support::load;
+
+mod support;
+
+// .line covers the instructions up to the next .line or the end, errors
+// print it with the function name in the backtrace.
+static LINES : &'static str = "
+        FNEW   2 divide
+        CALL   1 0
+        EXIT
+
+.func divide 0
+        CSHORT 3 1
+.line \"core.clj\" 12 3
+        CSHORT 4 0
+        MODVV  5 3 4
+        RET    5
+";
+
+#[test]
+fn line_table_locates_errors() {
+    let mut vm = load(LINES);
+    assert_eq!(vm.code.lines.len(), 1);
+    assert!(vm.code.line_entry(4).is_none());
+    assert_eq!(vm.code.line_entry(5).unwrap().location(), "core.clj:12:3".to_string());
+    assert_eq!(vm.code.line_entry(7).unwrap().location(), "core.clj:12:3".to_string());
+
+    let err = match vm.start() {
+        Ok(()) => panic!("no error"),
+        Err(err) => err
+    };
+    assert_eq!(err.ip, 6);
+    let msg = err.display(&vm.code).to_string();
+    assert!(msg.as_slice().contains("\n    in divide at 6 (core.clj:12:3)"));
+    assert!(msg.as_slice().contains("\n    in main at 1"));
+}
@@ -0,0 +1,31 @@
+extern crate clojit;
+
+use clojit::vm::{Array, Int};
+
+use support::{load, run, slot};
+
+mod support;
+
+// An array holding itself is printed once, the nested one as [...].
+#[test]
+fn array_cycle_prints_placeholder() {
+    let mut vm = load("EXIT");
+    let arr = vm.heap.alloc_array(vec![Int(1)]);
+    arr.borrow_mut().push(Array(arr.clone()));
+
+    assert_eq!(Array(arr.clone()).to_string(), "[1, [...]]".to_string());
+    arr.borrow_mut().pop();
+}
+
+// The same for an object that is its own field.
+#[test]
+fn object_cycle_prints_placeholder() {
+    let vm = run("
+.type Node next:mut
+        CTYPE  1 Node
+        ALLOC  2 1
+        SETFIELD 2 0 2
+        EXIT
+");
+    assert_eq!(slot(&vm, 2), "CljObject { cljtype: 0, fields: [{...}] }".to_string());
+}
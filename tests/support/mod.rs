@@ -0,0 +1,47 @@
+// Helpers shared by the integration tests, each test file includes them
+// with `mod support;`.
+#![allow(dead_code)]
+
+use clojit::asm;
+use clojit::vm::Vm;
+use clojit::error::VmResult;
+
+// Assembles `src` into a Vm that has not run yet.
+pub fn load(src: &str) -> Vm {
+    let (data, code, dd) = asm::assemble(src).unwrap();
+    Vm::new(data, code, dd)
+}
+
+// Runs `src` after `setup` changed the options of the Vm, returns the Vm
+// with the result of `start`.
+pub fn run_with(src: &str, setup: |&mut Vm|) -> (Vm, VmResult<()>) {
+    let mut vm = load(src);
+    setup(&mut vm);
+    let res = vm.start();
+    (vm, res)
+}
+
+// Runs `src`, which has to finish without an error.
+pub fn run(src: &str) -> Vm {
+    let (vm, res) = run_with(src, |_| ());
+    res.unwrap();
+    vm
+}
+
+// The printed value of absolute slot `i`.
+pub fn slot(vm: &Vm, i: uint) -> String {
+    vm.slots.slot[i].to_string()
+}
+
+// The printed values of the first `n` slots.
+pub fn slots(vm: &Vm, n: uint) -> Vec<String> {
+    vm.slots.slot[0..n].iter().map(|slot| slot.to_string()).collect()
+}
+
+// The printed ErrorKind of the error `start` returned.
+pub fn error_kind(res: VmResult<()>) -> String {
+    match res {
+        Ok(()) => panic!("ran without an error"),
+        Err(err) => err.kind.to_string()
+    }
+}
@@ -0,0 +1,73 @@
+extern crate clojit;
+
+use support::{run_with, slots};
+
+mod support;
+
+// A hot loop with a loop carried Int and Float. MOV 5 300 reads a slot past
+// the 8 bit range, slot 44 holds a value so reading a truncated D would show
+// in slot 5.
+static HOT_LOOP : &'static str = "
+        CSHORT 1 0
+        CSHORT 2 1
+        CINT   3 5000
+        CFLOAT 8 0.5
+        CFLOAT 9 0.0
+        CSHORT 44 7
+loop:   LOOP
+        ISLT   4 1 3
+        JUMPF  4 done
+        ADDVV  1 1 2
+        ADDVV  9 9 8
+        MOV    5 300
+        NEG    6 1
+        NOT    7 4
+        JUMP   0 loop
+done:   EXIT
+";
+
+fn run(src: &str, trace_jit: bool) -> Vec<String> {
+    let (vm, res) = run_with(src, |vm| {
+        vm.opts.trace_jit = trace_jit;
+        vm.opts.trace_jit_threshold = 10;
+    });
+    res.unwrap();
+    slots(&vm, 10)
+}
+
+#[test]
+fn trace_matches_interpreter() {
+    let interpreted = run(HOT_LOOP, false);
+    let traced = run(HOT_LOOP, true);
+
+    assert_eq!(interpreted[1], "5000".to_string());
+    assert_eq!(interpreted[5], "Nil".to_string());
+    assert_eq!(traced, interpreted);
+}
+
+// CSHORT operands are signed, a trace folds -1 and 0x8000 to the same
+// constants the interpreter loads.
+static SHORTS : &'static str = "
+        CSHORT 1 0
+        CSHORT 2 1
+        CINT   3 100
+loop:   LOOP
+        ISLT   4 1 3
+        JUMPF  4 done
+        ADDVV  1 1 2
+        CSHORT 5 -1
+        CSHORT 6 32768
+        ADDVV  7 5 6
+        JUMP   0 loop
+done:   EXIT
+";
+
+#[test]
+fn trace_shorts_are_signed() {
+    let interpreted = run(SHORTS, false);
+    let traced = run(SHORTS, true);
+
+    assert_eq!(interpreted[5], "-1".to_string());
+    assert_eq!(interpreted[7], "-32769".to_string());
+    assert_eq!(traced, interpreted);
+}
@@ -0,0 +1,75 @@
+extern crate clojit;
+
+use clojit::asm;
+use clojit::verify;
+
+mod support;
+
+fn errors(src: &str) -> Vec<String> {
+    let (data, code, dd) = asm::assemble(src).unwrap();
+    match verify::verify(&data, &code, &dd) {
+        Ok(()) => vec![],
+        Err(errors) => errors.iter().map(|err| err.msg.clone()).collect()
+    }
+}
+
+#[test]
+fn valid_program_verifies() {
+    let src = "
+.type Point x y
+        FNEW   2 f
+        CTYPE  3 Point
+        CALL   1 0
+        EXIT
+.func f 0
+        RET    0
+";
+    assert!(errors(src).is_empty());
+}
+
+// Every invalid operand is reported, not only the first.
+#[test]
+fn invalid_operands_are_reported() {
+    let src = "
+        FNEW   2 1
+        CTYPE  3 4
+        MFNEW  4 0
+        EXIT
+";
+    assert_eq!(errors(src), vec![
+        "function 1 is not a FUNCF or FUNCV".to_string(),
+        "type 4 out of range (0 types)".to_string(),
+        "no arity table entry for 0".to_string(),
+    ]);
+}
+
+// Vm::start verifies before running anything, a handler slot past the
+// frame is an error instead of a panic when it catches.
+#[test]
+fn start_rejects_unverified_code() {
+    let mut vm = support::load("
+try:    THROW  1
+end:    EXIT
+.catch try end end 6
+");
+    vm.code.handlers.get_mut(0).slot = 100000;
+    let kind = support::error_kind(vm.start());
+    assert!(kind.as_slice().starts_with("InvalidBytecode"));
+    assert!(kind.as_slice().contains("handler slot 100000 out of range"));
+}
+
+// The handler of main cannot continue in the code of a function.
+#[test]
+fn handler_target_in_other_function() {
+    let src = "
+try:    THROW  1
+end:    EXIT
+.catch try end other 6
+
+.func f 0
+other:  RET    0
+";
+    assert_eq!(errors(src), vec![
+        "handler target 3 is not in the function of 0..1".to_string(),
+    ]);
+}